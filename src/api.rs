@@ -0,0 +1,102 @@
+use roperator::prelude::*;
+use roperator::serde_json::{self, Value};
+
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SERVICE_ACCOUNT_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+const TIMEOUT_SECONDS: u64 = 10;
+
+/// Reads objects that are referenced by a Noria resource but are not its
+/// children, recipe ConfigMaps, Secrets and the StatefulSets that are backed up.
+/// Requests block the sync that makes them, for at most `TIMEOUT_SECONDS`.
+#[derive(Clone)]
+pub struct Api {
+    agent: ureq::Agent,
+    url: String,
+    token: Option<String>,
+}
+
+impl Api {
+    /// Talks to the API server as the operator's service account
+    pub fn in_cluster() -> Result<Api, Error> {
+        let host = env::var("KUBERNETES_SERVICE_HOST")?;
+        let port = env::var("KUBERNETES_SERVICE_PORT")?;
+        let token = fs::read_to_string(format!("{}/token", SERVICE_ACCOUNT_PATH))?;
+        let ca = fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT_PATH))?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_parsable_certificates(&rustls_pemfile::certs(&mut ca.as_slice())?);
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Api {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(TIMEOUT_SECONDS))
+                .tls_config(Arc::new(tls))
+                .build(),
+            url: format!("https://{}:{}", host, port),
+            token: Some(token.trim().to_string()),
+        })
+    }
+
+    /// Talks to a proxy that authenticates requests itself, such as `kubectl proxy`,
+    /// which has to be running before the operator starts syncing
+    pub fn proxy(url: &str) -> Api {
+        Api {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(TIMEOUT_SECONDS))
+                .build(),
+            url: url.trim_end_matches('/').to_string(),
+            token: None,
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<Option<Value>, Error> {
+        let mut request = self.agent.get(&format!("{}{}", self.url, path));
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        match request.call() {
+            Ok(response) => Ok(Some(serde_json::from_str(&response.into_string()?)?)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(err.to_string().into()),
+        }
+    }
+
+    /// A ConfigMap, or None when it does not exist
+    pub fn config_map(&self, namespace: &str, name: &str) -> Result<Option<Value>, Error> {
+        self.get(&format!(
+            "/api/v1/namespaces/{}/configmaps/{}",
+            namespace, name
+        ))
+    }
+
+    /// A Secret, or None when it does not exist
+    pub fn secret(&self, namespace: &str, name: &str) -> Result<Option<Value>, Error> {
+        self.get(&format!(
+            "/api/v1/namespaces/{}/secrets/{}",
+            namespace, name
+        ))
+    }
+
+    /// A StatefulSet, or None when it does not exist
+    pub fn statefulset(&self, namespace: &str, name: &str) -> Result<Option<Value>, Error> {
+        self.get(&format!(
+            "/apis/apps/v1/namespaces/{}/statefulsets/{}",
+            namespace, name
+        ))
+    }
+}
+
+/// Short hex digest identifying a content, such as a recipe
+pub fn digest(content: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, content);
+    hex::encode(&digest.as_ref()[..8])
+}
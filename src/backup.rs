@@ -0,0 +1,471 @@
+use super::api::Api;
+use super::error;
+use super::metrics;
+use super::server;
+use super::{NoriaBackup, S3Config, VolumeSnapshotConfig};
+use super::{AWS_CLI_IMAGE, BACKUP_OPERATOR_NAME, DEFAULT_NORIA_VERSION, NORIA_IMAGE};
+
+use roperator::prelude::*;
+use roperator::serde_json::{json, Value};
+
+use std::time::{Duration, Instant};
+
+const RESYNC_INTERVAL_SECONDS: u64 = 30;
+
+// Keys of the access key Secret, as named by the AWS CLI
+const ACCESS_KEY_ID_KEY: &str = "AWS_ACCESS_KEY_ID";
+const SECRET_ACCESS_KEY_KEY: &str = "AWS_SECRET_ACCESS_KEY";
+
+const DATA_PATH: &str = "/var/lib/noria";
+const ARCHIVE_PATH: &str = "/backup";
+
+/// Name of the object, or of the VolumeSnapshot, holding one replica's logs
+pub fn replica_name(backup_name: &str, replica: &str) -> String {
+    format!("{}-{}", backup_name, replica)
+}
+
+/// URL that object keys are appended to, the prefix is taken as is
+pub fn prefix_url(s3: &S3Config) -> String {
+    format!(
+        "s3://{}/{}",
+        s3.bucket,
+        s3.prefix.as_deref().unwrap_or_default()
+    )
+}
+
+/// URL of the archive holding one replica's logs. `replica` may be a shell
+/// expression that is expanded in the pod.
+pub fn object_url(s3: &S3Config, backup_name: &str, replica: &str) -> String {
+    format!("{}{}/{}.tar.gz", prefix_url(s3), backup_name, replica)
+}
+
+/// Credentials and region of the AWS CLI
+pub fn s3_env(s3: &S3Config) -> Vec<Value> {
+    let mut env = [ACCESS_KEY_ID_KEY, SECRET_ACCESS_KEY_KEY]
+        .iter()
+        .map(|key| {
+            json!({
+                "name": key,
+                "valueFrom": {
+                    "secretKeyRef": {
+                        "name": s3.secret,
+                        "key": key,
+                    }
+                }
+            })
+        })
+        .collect::<Vec<Value>>();
+
+    if let Some(region) = &s3.region {
+        env.push(json!({
+            "name": "AWS_DEFAULT_REGION",
+            "value": region,
+        }));
+    }
+
+    env
+}
+
+pub struct Config<'svc> {
+    name: &'svc str,
+    statefulset_name: String,
+    replicas: usize,
+    claim_template: Option<Value>,
+    volume_snapshot: Option<&'svc VolumeSnapshotConfig>,
+    s3: Option<&'svc S3Config>,
+}
+
+pub fn create_config<'svc>(
+    api: &Api,
+    request: &SyncRequest,
+    backup: &'svc NoriaBackup,
+    volume_snapshots: bool,
+) -> Result<Config<'svc>, Error> {
+    let spec = &backup.spec;
+
+    if spec.volume_snapshot.is_none() && spec.s3.is_none() {
+        return Err(Box::new(error::BackupMethodError {
+            name: backup.metadata.name.clone(),
+        }));
+    }
+    if !volume_snapshots {
+        return Err(Box::new(error::BackupSnapshotsError {
+            name: backup.metadata.name.clone(),
+        }));
+    }
+
+    let statefulset_name = server::name(&spec.noria, &spec.deployment);
+    let statefulset = api.statefulset(&backup.metadata.namespace, &statefulset_name)?;
+
+    // replicas are counted once, scaling the StatefulSet afterwards does not
+    // change a backup in progress
+    let replicas = match request
+        .parent
+        .pointer("/status/replicas")
+        .and_then(Value::as_array)
+    {
+        Some(replicas) if !replicas.is_empty() => replicas.len(),
+        _ => statefulset
+            .as_ref()
+            .and_then(|s| s.pointer("/spec/replicas"))
+            .and_then(Value::as_u64)
+            .ok_or_else(|| error::BackupStatefulSetError {
+                name: backup.metadata.name.clone(),
+                statefulset: statefulset_name.clone(),
+            })? as usize,
+    };
+
+    Ok(Config {
+        name: &backup.metadata.name,
+        statefulset_name,
+        replicas,
+        claim_template: statefulset
+            .as_ref()
+            .and_then(|s| s.pointer("/spec/volumeClaimTemplates/0/spec"))
+            .cloned(),
+        volume_snapshot: spec.volume_snapshot.as_ref(),
+        s3: spec.s3.as_ref(),
+    })
+}
+
+// Backup of one replica, and whether it is done, None while in progress
+struct Replica {
+    children: Vec<Value>,
+    result: Option<Result<(), String>>,
+}
+
+impl<'svc> Config<'svc> {
+    fn volume_snapshot(&self, namespace: &str, replica: usize) -> Value {
+        let mut snapshot = json!({
+            "apiVersion": "snapshot.storage.k8s.io/v1",
+            "kind": "VolumeSnapshot",
+            "metadata": {
+                "name": replica_name(self.name, &replica.to_string()),
+                "namespace": namespace,
+            },
+            "spec": {
+                "source": {
+                    "persistentVolumeClaimName": server::claim_name(&self.statefulset_name, replica)
+                }
+            }
+        });
+
+        if let Some(VolumeSnapshotConfig {
+            class_name: Some(class_name),
+        }) = self.volume_snapshot
+        {
+            snapshot["spec"]["volumeSnapshotClassName"] = json!(class_name);
+        }
+
+        snapshot
+    }
+
+    // Volume restored from a replica's VolumeSnapshot, in the class of the
+    // StatefulSet's volumes
+    fn snapshot_claim(
+        &self,
+        namespace: &str,
+        replica: usize,
+        restore_size: Option<&Value>,
+    ) -> Value {
+        let mut claim = json!({
+            "apiVersion": "v1",
+            "kind": "PersistentVolumeClaim",
+            "metadata": {
+                "name": replica_name(self.name, &replica.to_string()),
+                "namespace": namespace,
+            },
+            "spec": {
+                "accessModes": ["ReadWriteOnce"],
+                "dataSource": {
+                    "apiGroup": "snapshot.storage.k8s.io",
+                    "kind": "VolumeSnapshot",
+                    "name": replica_name(self.name, &replica.to_string()),
+                }
+            }
+        });
+
+        if let Some(template) = &self.claim_template {
+            for field in &["accessModes", "storageClassName", "resources"] {
+                if let Some(value) = template.get(*field) {
+                    claim["spec"][*field] = value.clone();
+                }
+            }
+        }
+        if let Some(restore_size) = restore_size {
+            claim["spec"]["resources"]["requests"]["storage"] = restore_size.clone();
+        }
+
+        claim
+    }
+
+    // Job archiving a replica's logs and uploading them. The logs are read
+    // from a volume restored from the replica's VolumeSnapshot, as noria-server
+    // keeps writing to its own.
+    fn upload_job(&self, s3: &S3Config, namespace: &str, replica: usize) -> Value {
+        json!({
+            "apiVersion": "batch/v1",
+            "kind": "Job",
+            "metadata": {
+                "name": replica_name(self.name, &replica.to_string()),
+                "namespace": namespace,
+            },
+            "spec": {
+                "backoffLimit": 3,
+                "template": {
+                    "spec": {
+                        "restartPolicy": "OnFailure",
+                        "initContainers": [{
+                            "name": "archive",
+                            "image": format!("{}:{}", NORIA_IMAGE, DEFAULT_NORIA_VERSION),
+                            "command": [
+                                "tar",
+                                "czf",
+                                format!("{}/data.tar.gz", ARCHIVE_PATH),
+                                "-C",
+                                DATA_PATH,
+                                "."
+                            ],
+                            "volumeMounts": [{
+                                "mountPath": DATA_PATH,
+                                "name": "data",
+                                "readOnly": true
+                            }, {
+                                "mountPath": ARCHIVE_PATH,
+                                "name": "archive"
+                            }]
+                        }],
+                        "containers": [{
+                            "name": "upload",
+                            "image": AWS_CLI_IMAGE,
+                            "args": [
+                                "s3",
+                                "cp",
+                                format!("{}/data.tar.gz", ARCHIVE_PATH),
+                                object_url(s3, self.name, &replica.to_string()),
+                                "--endpoint-url",
+                                s3.endpoint
+                            ],
+                            "env": s3_env(s3),
+                            "volumeMounts": [{
+                                "mountPath": ARCHIVE_PATH,
+                                "name": "archive",
+                                "readOnly": true
+                            }]
+                        }],
+                        "volumes": [{
+                            "name": "data",
+                            "persistentVolumeClaim": {
+                                "claimName": replica_name(self.name, &replica.to_string()),
+                                "readOnly": true
+                            }
+                        }, {
+                            "name": "archive",
+                            "emptyDir": {}
+                        }]
+                    }
+                }
+            }
+        })
+    }
+
+    // Every replica is snapshotted. With s3, the snapshot is restored to a
+    // volume that is archived, and both are released once the upload is done,
+    // unless volume_snapshot asks to keep the snapshot.
+    fn replica(&self, request: &SyncRequest, namespace: &str, replica: usize) -> Replica {
+        let snapshot = self.volume_snapshot(namespace, replica);
+
+        let s3 = match self.s3 {
+            Some(s3) => s3,
+            None => {
+                let result = completed(request, &snapshot);
+                return Replica {
+                    children: vec![snapshot],
+                    result,
+                };
+            }
+        };
+
+        let job = self.upload_job(s3, namespace, replica);
+        if let Some(result) = completed(request, &job) {
+            let children = match self.volume_snapshot {
+                Some(_) => vec![snapshot, job],
+                None => vec![job],
+            };
+            return Replica {
+                children,
+                result: Some(result),
+            };
+        }
+
+        match completed(request, &snapshot) {
+            Some(Ok(())) => (),
+            result => {
+                return Replica {
+                    children: vec![snapshot],
+                    result,
+                }
+            }
+        }
+
+        let restore_size =
+            observed(request, &snapshot).and_then(|s| s.pointer("/status/restoreSize"));
+        let claim = self.snapshot_claim(namespace, replica, restore_size);
+
+        Replica {
+            children: vec![snapshot, claim, job],
+            result: None,
+        }
+    }
+
+    fn replicas(&self, request: &SyncRequest, namespace: &str) -> Vec<Replica> {
+        (0..self.replicas)
+            .map(|replica| self.replica(request, namespace, replica))
+            .collect()
+    }
+}
+
+// The child as last observed, if it exists
+fn observed<'a>(request: &'a SyncRequest, child: &Value) -> Option<&'a Value> {
+    let api_version = child["apiVersion"].as_str()?;
+    let kind = child["kind"].as_str()?;
+    let name = child["metadata"]["name"].as_str()?;
+    let namespace = child["metadata"]["namespace"].as_str()?;
+
+    request
+        .children()
+        .of_type((api_version, kind))
+        .get(namespace, name)
+}
+
+// Whether a Job or VolumeSnapshot is done, None while it is in progress
+fn completed(request: &SyncRequest, child: &Value) -> Option<Result<(), String>> {
+    let kind = child["kind"].as_str()?;
+    let name = child["metadata"]["name"].as_str()?;
+    let existing = observed(request, child)?;
+
+    if kind == "VolumeSnapshot" {
+        if let Some(message) = existing.pointer("/status/error/message") {
+            return Some(Err(format!("{}: {}", name, message)));
+        }
+        return match existing.pointer("/status/readyToUse") {
+            Some(Value::Bool(true)) => Some(Ok(())),
+            _ => None,
+        };
+    }
+
+    let condition = |type_: &str| {
+        existing
+            .pointer("/status/conditions")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .any(|c| c["type"] == type_ && c["status"] == "True")
+    };
+
+    if condition("Complete") {
+        Some(Ok(()))
+    } else if condition("Failed") {
+        Some(Err(format!("{}: upload failed", name)))
+    } else {
+        None
+    }
+}
+
+fn create_status(backup_name: &str, replicas: &[Replica]) -> Value {
+    let errors = replicas
+        .iter()
+        .filter_map(|replica| replica.result.as_ref().and_then(|r| r.as_ref().err()))
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+    let done = replicas
+        .iter()
+        .filter(|replica| replica.result.is_some())
+        .count();
+
+    let (phase, message) = if !errors.is_empty() {
+        ("Failed", errors.join(", "))
+    } else if done == replicas.len() {
+        ("Completed", String::from("Backup complete"))
+    } else {
+        (
+            "Running",
+            format!("{}/{} replicas backed up", done, replicas.len()),
+        )
+    };
+
+    json!({
+        "phase": phase,
+        "message": message,
+        "replicas": replicas
+            .iter()
+            .enumerate()
+            .map(|(i, replica)| json!({
+                "name": replica_name(backup_name, &i.to_string()),
+                "completed": matches!(replica.result, Some(Ok(()))),
+            }))
+            .collect::<Vec<Value>>(),
+    })
+}
+
+pub fn handle_sync(
+    api: &Api,
+    volume_snapshots: bool,
+    request: &SyncRequest,
+) -> Result<SyncResponse, Error> {
+    let started = Instant::now();
+
+    let response = sync(api, volume_snapshots, request);
+
+    metrics::record_sync(
+        BACKUP_OPERATOR_NAME,
+        request,
+        started.elapsed(),
+        response.as_ref().ok(),
+    );
+
+    response
+}
+
+fn sync(api: &Api, volume_snapshots: bool, request: &SyncRequest) -> Result<SyncResponse, Error> {
+    let backup: NoriaBackup = request.deserialize_parent()?;
+
+    let replicas = create_config(api, request, &backup, volume_snapshots)?
+        .replicas(request, &backup.metadata.namespace);
+
+    let status = create_status(&backup.metadata.name, &replicas);
+    let children = replicas
+        .into_iter()
+        .flat_map(|replica| replica.children)
+        .collect();
+
+    // resync until every replica is backed up
+    let resync = if status["phase"] == "Running" {
+        Some(Duration::from_secs(RESYNC_INTERVAL_SECONDS))
+    } else {
+        None
+    };
+
+    Ok(SyncResponse {
+        status,
+        children,
+        resync,
+    })
+}
+
+pub fn handle_error(request: &SyncRequest, err: Error) -> (Value, Option<Duration>) {
+    log::error!(
+        "Failed to process backup request: {:?}\nCause: {:?}",
+        request,
+        err
+    );
+
+    metrics::record_error(BACKUP_OPERATOR_NAME, request, &err);
+
+    let status = json!({
+        "phase": "Error",
+        "message": err.to_string(),
+    });
+
+    (status, None)
+}
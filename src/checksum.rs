@@ -0,0 +1,47 @@
+use super::api::{self, Api};
+
+use roperator::prelude::*;
+use roperator::serde_json::{json, Value};
+
+use std::collections::HashMap;
+
+/// Prefix of the pod template annotations carrying the checksum of each
+/// Secret mounted by the pods, suffixed with what the Secret is used for, such
+/// as `credentials` or `tls`. Secret names could exceed the length allowed for
+/// annotation keys.
+pub const CHECKSUM_ANNOTATION_PREFIX: &str = "noria-operator.io/checksum-";
+
+pub fn annotation(purpose: &str) -> String {
+    format!("{}{}", CHECKSUM_ANNOTATION_PREFIX, purpose)
+}
+
+/// Checksums of the given Secrets by name, read on every sync since Secrets
+/// are not children. A Secret that does not exist yet, such as a pending
+/// certificate, is left out.
+pub fn read(
+    api: &Api,
+    namespace: &str,
+    secrets: &[String],
+) -> Result<HashMap<String, String>, Error> {
+    let mut checksums = HashMap::new();
+
+    for secret in secrets {
+        if let Some(existing) = api.secret(namespace, secret)? {
+            let checksum = api::digest(existing["data"].to_string().as_bytes());
+            checksums.insert(secret.clone(), checksum);
+        }
+    }
+
+    Ok(checksums)
+}
+
+/// Pod template annotations with the checksums of the given Secrets, by
+/// purpose, so that the pods roll when one of them changes
+pub fn pod_annotations(checksums: &HashMap<String, String>, secrets: &[(&str, &str)]) -> Value {
+    let mut annotations = json!({});
+    for (purpose, secret) in secrets {
+        let checksum = checksums.get(*secret).cloned().unwrap_or_default();
+        annotations[&annotation(purpose)] = json!(checksum);
+    }
+    annotations
+}
@@ -0,0 +1,146 @@
+use super::model::{NoriaBackupSpec, NoriaSpec};
+use super::model::{PARENT_TYPE_NORIA_BACKUP, PARENT_TYPE_NORIA_CLUSTER};
+
+use roperator::serde_json::{json, Value};
+
+use schemars::gen::SchemaSettings;
+use schemars::JsonSchema;
+
+/// OpenAPI v3 structural schema of a spec type, as served by the CRD.
+/// Kubernetes doesn't resolve references, so every type is inlined.
+fn schema<T: JsonSchema>() -> Value {
+    let generator = SchemaSettings::openapi3()
+        .with(|settings| settings.inline_subschemas = true)
+        .into_generator();
+    let mut schema = json!(generator.into_root_schema_for::<T>().schema);
+
+    // the name of the Rust type means nothing to users
+    if let Some(schema) = schema.as_object_mut() {
+        schema.remove("title");
+    }
+    schema
+}
+
+pub fn create_crd() -> Value {
+    let mut api_version = PARENT_TYPE_NORIA_CLUSTER.api_version.splitn(2, '/');
+    let group = api_version.next().unwrap_or_default();
+    let version = api_version.next().unwrap_or_default();
+
+    json!({
+        "apiVersion": "apiextensions.k8s.io/v1",
+        "kind": "CustomResourceDefinition",
+        "metadata": {
+            "name": format!("{}.{}", PARENT_TYPE_NORIA_CLUSTER.plural_kind, group),
+        },
+        "spec": {
+            "group": group,
+            "versions": [{
+                "name": version,
+                "served": true,
+                "storage": true,
+                "schema": {
+                    "openAPIV3Schema": {
+                        "type": "object",
+                        "required": ["spec"],
+                        "properties": {
+                            "spec": schema::<NoriaSpec>(),
+                            "status": {
+                                "type": "object",
+                                "x-kubernetes-preserve-unknown-fields": true,
+                            }
+                        }
+                    }
+                },
+                "subresources": {
+                    "status": {}
+                },
+                "additionalPrinterColumns": [{
+                    "name": "Phase",
+                    "type": "string",
+                    "jsonPath": ".status.phase",
+                }, {
+                    "name": "Ready",
+                    "type": "string",
+                    "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status",
+                }, {
+                    "name": "Message",
+                    "type": "string",
+                    "priority": 1,
+                    "jsonPath": ".status.message",
+                }, {
+                    "name": "Age",
+                    "type": "date",
+                    "jsonPath": ".metadata.creationTimestamp",
+                }]
+            }],
+            "scope": "Namespaced",
+            "names": {
+                "kind": "Noria",
+                "plural": PARENT_TYPE_NORIA_CLUSTER.plural_kind,
+                "singular": "noria",
+                "shortNames": ["noria"],
+            }
+        }
+    })
+}
+
+pub fn create_backup_crd() -> Value {
+    let mut api_version = PARENT_TYPE_NORIA_BACKUP.api_version.splitn(2, '/');
+    let group = api_version.next().unwrap_or_default();
+    let version = api_version.next().unwrap_or_default();
+
+    json!({
+        "apiVersion": "apiextensions.k8s.io/v1",
+        "kind": "CustomResourceDefinition",
+        "metadata": {
+            "name": format!("{}.{}", PARENT_TYPE_NORIA_BACKUP.plural_kind, group),
+        },
+        "spec": {
+            "group": group,
+            "versions": [{
+                "name": version,
+                "served": true,
+                "storage": true,
+                "schema": {
+                    "openAPIV3Schema": {
+                        "type": "object",
+                        "required": ["spec"],
+                        "properties": {
+                            "spec": schema::<NoriaBackupSpec>(),
+                            "status": {
+                                "type": "object",
+                                "x-kubernetes-preserve-unknown-fields": true,
+                            }
+                        }
+                    }
+                },
+                "subresources": {
+                    "status": {}
+                },
+                "additionalPrinterColumns": [{
+                    "name": "Noria",
+                    "type": "string",
+                    "jsonPath": ".spec.noria",
+                }, {
+                    "name": "Deployment",
+                    "type": "string",
+                    "jsonPath": ".spec.deployment",
+                }, {
+                    "name": "Phase",
+                    "type": "string",
+                    "jsonPath": ".status.phase",
+                }, {
+                    "name": "Age",
+                    "type": "date",
+                    "jsonPath": ".metadata.creationTimestamp",
+                }]
+            }],
+            "scope": "Namespaced",
+            "names": {
+                "kind": PARENT_TYPE_NORIA_BACKUP.kind,
+                "plural": PARENT_TYPE_NORIA_BACKUP.plural_kind,
+                "singular": "noriabackup",
+            }
+        }
+    })
+}
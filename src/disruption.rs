@@ -0,0 +1,52 @@
+use super::DisruptionBudgetConfig;
+
+use roperator::serde_json::{json, Value};
+
+const DEFAULT_MAX_UNAVAILABLE: u64 = 1;
+
+/// Budget fields of a PodDisruptionBudget spec, None if the spec switches it
+/// off. Without a budget in the spec, `default` decides.
+pub fn budget(disruption_budget: Option<&DisruptionBudgetConfig>, default: bool) -> Option<Value> {
+    match disruption_budget {
+        Some(DisruptionBudgetConfig {
+            enabled: Some(false),
+            ..
+        }) => None,
+        Some(DisruptionBudgetConfig {
+            min_available: Some(min_available),
+            ..
+        }) => Some(json!({ "minAvailable": min_available })),
+        Some(DisruptionBudgetConfig {
+            max_unavailable: Some(max_unavailable),
+            ..
+        }) => Some(json!({ "maxUnavailable": max_unavailable })),
+        Some(_) => Some(json!({ "maxUnavailable": DEFAULT_MAX_UNAVAILABLE })),
+        None if default => Some(json!({ "maxUnavailable": DEFAULT_MAX_UNAVAILABLE })),
+        None => None,
+    }
+}
+
+/// PodDisruptionBudget over the pods matching `labels`
+pub fn pod_disruption_budget(name: &str, namespace: &str, labels: Value, budget: Value) -> Value {
+    let mut pdb = json!({
+        "apiVersion": "policy/v1",
+        "kind": "PodDisruptionBudget",
+        "metadata": {
+            "name": name,
+            "namespace": namespace,
+        },
+        "spec": {
+            "selector": {
+                "matchLabels": labels
+            }
+        }
+    });
+
+    if let Some(fields) = budget.as_object() {
+        for (field, value) in fields {
+            pdb["spec"][field] = value.clone();
+        }
+    }
+
+    pdb
+}
@@ -13,3 +13,165 @@ impl Display for DeploymentIdDashError {
 }
 
 impl Error for DeploymentIdDashError {}
+
+#[derive(Debug)]
+pub struct ValidationError {
+    pub errors: Vec<String>,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Invalid Noria: {}", self.errors.join(", "))
+    }
+}
+
+impl Error for ValidationError {}
+
+#[derive(Debug)]
+pub struct ZookeeperReplicasError {
+    pub replicas: usize,
+}
+
+impl Display for ZookeeperReplicasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "ZooKeeper replicas must be an odd number greater than zero ({})",
+            self.replicas
+        )
+    }
+}
+
+impl Error for ZookeeperReplicasError {}
+
+#[derive(Debug)]
+pub struct ZookeeperScalingError {
+    pub from: usize,
+    pub to: usize,
+}
+
+impl Display for ZookeeperScalingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "Cannot scale ZooKeeper from {} to {} members without losing quorum",
+            self.from, self.to
+        )
+    }
+}
+
+impl Error for ZookeeperScalingError {}
+
+#[derive(Debug)]
+pub struct ZookeeperTlsVersionError {
+    pub version: String,
+}
+
+impl Display for ZookeeperTlsVersionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "ZooKeeper TLS needs Confluent Platform 5.4 or later ({})",
+            self.version
+        )
+    }
+}
+
+impl Error for ZookeeperTlsVersionError {}
+
+#[derive(Debug)]
+pub struct MysqlTlsCredentialsError {
+    pub id: String,
+}
+
+impl Display for MysqlTlsCredentialsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "noria-mysql TLS needs credentials to authenticate clients ({})",
+            self.id
+        )
+    }
+}
+
+impl Error for MysqlTlsCredentialsError {}
+
+#[derive(Debug)]
+pub struct BackupMethodError {
+    pub name: String,
+}
+
+impl Display for BackupMethodError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "Backup {} must set volume_snapshot, s3 or both",
+            self.name
+        )
+    }
+}
+
+impl Error for BackupMethodError {}
+
+#[derive(Debug)]
+pub struct BackupSnapshotsError {
+    pub name: String,
+}
+
+impl Display for BackupSnapshotsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "Backup {} needs VolumeSnapshots, enabled with --volume-snapshots",
+            self.name
+        )
+    }
+}
+
+impl Error for BackupSnapshotsError {}
+
+#[derive(Debug)]
+pub struct BackupStatefulSetError {
+    pub name: String,
+    pub statefulset: String,
+}
+
+impl Display for BackupStatefulSetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "Backup {} found no StatefulSet {} to back up",
+            self.name, self.statefulset
+        )
+    }
+}
+
+impl Error for BackupStatefulSetError {}
+
+/// Kind of a sync error, as reported in the operator's metrics
+pub fn kind(err: &(dyn Error + 'static)) -> &'static str {
+    if err.is::<DeploymentIdDashError>() {
+        "DeploymentIdDashError"
+    } else if err.is::<ValidationError>() {
+        "ValidationError"
+    } else if err.is::<ZookeeperReplicasError>() {
+        "ZookeeperReplicasError"
+    } else if err.is::<ZookeeperScalingError>() {
+        "ZookeeperScalingError"
+    } else if err.is::<ZookeeperTlsVersionError>() {
+        "ZookeeperTlsVersionError"
+    } else if err.is::<MysqlTlsCredentialsError>() {
+        "MysqlTlsCredentialsError"
+    } else if err.is::<BackupMethodError>() {
+        "BackupMethodError"
+    } else if err.is::<BackupSnapshotsError>() {
+        "BackupSnapshotsError"
+    } else if err.is::<BackupStatefulSetError>() {
+        "BackupStatefulSetError"
+    } else if err.is::<roperator::serde_json::Error>() {
+        // the parent does not match the CRD schema
+        "DeserializationError"
+    } else {
+        "Other"
+    }
+}
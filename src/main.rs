@@ -1,22 +1,43 @@
+mod api;
+mod backup;
+mod checksum;
+mod crd;
+mod disruption;
 mod error;
+mod metrics;
 mod model;
+mod monitoring;
 mod mysql;
+mod recipe;
+mod render;
+mod resources;
+mod restore;
+mod scheduling;
 mod server;
+mod snapshot;
+mod status;
+mod storage;
+mod tls;
 mod ui;
+mod update;
+mod validate;
+mod webhook;
 mod zookeeper;
 
-use std::time::Duration;
-use std::{env, process};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
 
 use k8s_types::apps::v1 as apps;
 use k8s_types::core::v1 as core;
-use k8s_types::extensions::v1beta1 as beta;
 
+use api::Api;
 use model::*;
 
 use roperator::prelude::*;
 use roperator::runner::run_operator_with_client_config;
-use roperator::serde_json::{json, Value};
+use roperator::serde_json::Value;
 
 use structopt::clap::arg_enum;
 use structopt::StructOpt;
@@ -24,13 +45,21 @@ use structopt::StructOpt;
 use log::*;
 
 const OPERATOR_NAME: &str = "noria-operator";
+const BACKUP_OPERATOR_NAME: &str = "noria-backup-operator";
 const NORIA_IMAGE: &str = "fussybeaver/noria";
 const CONFLUENT_ZOOKEEPER_IMAGE: &str = "confluentinc/cp-zookeeper";
-
-const ZOOKEEPER_CLIENT_SERVICE_NAME: &str = "zookeeper-noria-client";
+const KUBECTL_IMAGE: &str = "bitnami/kubectl";
+const MYSQL_CLIENT_IMAGE: &str = "mysql:5.7";
+const PROXYSQL_IMAGE: &str = "proxysql/proxysql:2.5.5";
+const OAUTH2_PROXY_IMAGE: &str = "quay.io/oauth2-proxy/oauth2-proxy:v7.2.1";
+const NGINX_IMAGE: &str = "nginx:1.21-alpine";
+const AWS_CLI_IMAGE: &str = "amazon/aws-cli:2.7.0";
+const JMX_EXPORTER_IMAGE: &str = "bitnami/jmx-exporter:0.17.0";
 
 const DEFAULT_NORIA_VERSION: &str = "0.4.1";
 
+const RESYNC_INTERVAL_SECONDS: u64 = 30;
+
 arg_enum! {
     #[derive(Debug)]
     enum SourceConfig {
@@ -40,9 +69,63 @@ arg_enum! {
 }
 
 #[derive(StructOpt, Debug)]
-struct Opt {
-    #[structopt(possible_values = &SourceConfig::variants(), case_insensitive = true)]
-    conf: SourceConfig,
+enum Opt {
+    /// Run the operator against a cluster
+    Run {
+        #[structopt(possible_values = &SourceConfig::variants(), case_insensitive = true)]
+        conf: SourceConfig,
+        /// Port of the validating admission webhook
+        #[structopt(long = "webhook-port", default_value = "8443")]
+        webhook_port: u16,
+        /// PEM certificate of the admission webhook, serving it requires --tls-key as well
+        #[structopt(long = "tls-cert", parse(from_os_str))]
+        tls_cert: Option<PathBuf>,
+        /// PEM private key of the admission webhook
+        #[structopt(long = "tls-key", parse(from_os_str))]
+        tls_key: Option<PathBuf>,
+        /// Port serving the operator's Prometheus metrics
+        #[structopt(long = "metrics-port", default_value = "9090")]
+        metrics_port: u16,
+        /// Manage cert-manager Certificates for components that name an issuer
+        #[structopt(long = "cert-manager")]
+        cert_manager: bool,
+        /// Manage ServiceMonitors for Noria resources with a monitoring section
+        #[structopt(long = "service-monitors")]
+        service_monitors: bool,
+        /// Back up noria-server volumes to VolumeSnapshots, where the snapshot CRDs are installed
+        #[structopt(long = "volume-snapshots")]
+        volume_snapshots: bool,
+        /// Address of a proxy to the API server, such as `kubectl proxy`, through which the
+        /// kubeconfig source reads recipe ConfigMaps and Secrets
+        #[structopt(long = "api-proxy", default_value = "http://127.0.0.1:8001")]
+        api_proxy: String,
+    },
+    /// Print the Noria and NoriaBackup CustomResourceDefinitions
+    Crd,
+    /// Print the children of a Noria manifest without contacting a cluster
+    Render {
+        /// Noria manifest to render
+        #[structopt(short = "f", long = "file", parse(from_os_str))]
+        file: PathBuf,
+        /// Namespace used when the manifest does not set one
+        #[structopt(short = "n", long = "namespace", default_value = "default")]
+        namespace: String,
+    },
+}
+
+// Before the subcommands, the source config was the only argument. Existing
+// deployments passing it alone keep working as `run <source config>`.
+fn args() -> Vec<String> {
+    let mut args: Vec<String> = env::args().collect();
+    let legacy = args.get(1).map_or(false, |arg| {
+        SourceConfig::variants()
+            .iter()
+            .any(|variant| variant.eq_ignore_ascii_case(arg))
+    });
+    if legacy {
+        args.insert(1, "run".to_string());
+    }
+    args
 }
 
 trait Builder {
@@ -52,10 +135,9 @@ trait Builder {
 fn handle_error(request: &SyncRequest, err: Error) -> (Value, Option<Duration>) {
     log::error!("Failed to process request: {:?}\nCause: {:?}", request, err);
 
-    let status = json!({
-        "message": err.to_string(),
-        "phase": "Error",
-    });
+    metrics::record_error(OPERATOR_NAME, request, &err);
+
+    let status = status::create_error_status(request, &err.to_string());
 
     (status, None)
 }
@@ -67,38 +149,319 @@ fn main() {
 
     env_logger::init();
 
-    let opt = Opt::from_args();
-
-    let operator_config = OperatorConfig::new(OPERATOR_NAME, model::PARENT_TYPE_NORIA_CLUSTER)
-        .with_child(apps::StatefulSet, ChildConfig::recreate())
-        .with_child(beta::Deployment, ChildConfig::recreate())
-        .with_child(core::Service, ChildConfig::recreate())
-        .with_child(core::ConfigMap, ChildConfig::recreate());
-
-    let client_config = match opt.conf {
-        SourceConfig::Kubeconfig => {
-            ClientConfig::from_kubeconfig(OPERATOR_NAME).expect("Couldn't load client config")
+    let opt = Opt::from_iter(args());
+
+    let (conf, api_proxy, cert_manager, service_monitors, volume_snapshots) = match opt {
+        Opt::Run {
+            conf,
+            webhook_port,
+            tls_cert,
+            tls_key,
+            metrics_port,
+            cert_manager,
+            service_monitors,
+            volume_snapshots,
+            api_proxy,
+        } => {
+            thread::spawn(move || {
+                let err = metrics::run(metrics_port);
+                log::error!("Error serving metrics: {}", err);
+                process::exit(1);
+            });
+
+            match (tls_cert, tls_key) {
+                (Some(tls_cert), Some(tls_key)) => {
+                    thread::spawn(move || {
+                        let err = webhook::run(webhook_port, &tls_cert, &tls_key);
+                        log::error!("Error running admission webhook: {}", err);
+                        process::exit(1);
+                    });
+                }
+                (None, None) => (),
+                _ => {
+                    log::error!("The admission webhook needs both --tls-cert and --tls-key");
+                    process::exit(1);
+                }
+            }
+            (
+                conf,
+                api_proxy,
+                cert_manager,
+                service_monitors,
+                volume_snapshots,
+            )
         }
-        SourceConfig::Serviceaccount => {
-            ClientConfig::from_service_account(OPERATOR_NAME).expect("Couldn't load client config")
+        Opt::Crd => {
+            println!(
+                "{}",
+                serde_yaml::to_string(&crd::create_crd()).expect("Couldn't serialize CRD")
+            );
+            println!(
+                "{}",
+                serde_yaml::to_string(&crd::create_backup_crd()).expect("Couldn't serialize CRD")
+            );
+            return;
+        }
+        Opt::Render { file, namespace } => {
+            match render::render(&file, &namespace) {
+                Ok(rendered) => print!("{}", rendered),
+                Err(err) => {
+                    log::error!("Failed to render {}: {}", file.display(), err);
+                    process::exit(1);
+                }
+            }
+            return;
         }
     };
 
+    let mut operator_config = OperatorConfig::new(OPERATOR_NAME, model::PARENT_TYPE_NORIA_CLUSTER)
+        .with_child(apps::StatefulSet, ChildConfig::replace())
+        .with_child(apps::Deployment, ChildConfig::replace())
+        .with_child(core::Service, ChildConfig::replace())
+        .with_child(core::ConfigMap, ChildConfig::recreate())
+        .with_child(model::CHILD_TYPE_JOB, ChildConfig::recreate())
+        .with_child(model::CHILD_TYPE_CRON_JOB, ChildConfig::replace())
+        .with_child(model::CHILD_TYPE_INGRESS, ChildConfig::replace())
+        .with_child(
+            model::CHILD_TYPE_POD_DISRUPTION_BUDGET,
+            ChildConfig::replace(),
+        )
+        .with_child(
+            model::CHILD_TYPE_HORIZONTAL_POD_AUTOSCALER,
+            ChildConfig::replace(),
+        )
+        .with_child(model::CHILD_TYPE_SERVICE_ACCOUNT, ChildConfig::recreate())
+        .with_child(model::CHILD_TYPE_ROLE, ChildConfig::recreate())
+        .with_child(model::CHILD_TYPE_ROLE_BINDING, ChildConfig::recreate());
+
+    // the Certificate type only exists where cert-manager is installed
+    if cert_manager {
+        operator_config =
+            operator_config.with_child(model::CHILD_TYPE_CERTIFICATE, ChildConfig::replace());
+    }
+
+    // the ServiceMonitor type only exists where the Prometheus operator is installed
+    if service_monitors {
+        operator_config =
+            operator_config.with_child(model::CHILD_TYPE_SERVICE_MONITOR, ChildConfig::replace());
+    }
+
+    let mut backup_operator_config =
+        OperatorConfig::new(BACKUP_OPERATOR_NAME, model::PARENT_TYPE_NORIA_BACKUP)
+            .with_child(model::CHILD_TYPE_JOB, ChildConfig::recreate());
+
+    // the VolumeSnapshot type only exists where the snapshot CRDs are installed,
+    // and uploads are made from volumes restored from them
+    if volume_snapshots {
+        backup_operator_config = backup_operator_config
+            .with_child(model::CHILD_TYPE_VOLUME_SNAPSHOT, ChildConfig::replace())
+            .with_child(
+                model::CHILD_TYPE_PERSISTENT_VOLUME_CLAIM,
+                ChildConfig::recreate(),
+            );
+    }
+
+    let client_config = load_client_config(&conf, OPERATOR_NAME);
+
     info!("{:?}", client_config);
 
+    let backup_client_config = load_client_config(&conf, BACKUP_OPERATOR_NAME);
+
+    let api = load_api(&conf, &api_proxy);
+    let backup_api = api.clone();
+
+    // NoriaBackups are handled by a second operator next to the Noria one
+    thread::spawn(move || {
+        let err = run_operator_with_client_config(
+            backup_operator_config,
+            backup_client_config,
+            (
+                move |request: &SyncRequest| {
+                    backup::handle_sync(&backup_api, volume_snapshots, request)
+                },
+                backup::handle_error,
+            ),
+        );
+        log::error!("Error running backup operator: {}", err);
+        process::exit(1);
+    });
+
     let err = run_operator_with_client_config(
         operator_config,
         client_config,
-        (handle_sync, handle_error),
+        (
+            move |request: &SyncRequest| handle_sync(&api, service_monitors, request),
+            handle_error,
+        ),
     );
 
     log::error!("Error running operator: {}", err);
     process::exit(1);
 }
 
-fn handle_sync(request: &SyncRequest) -> Result<SyncResponse, Error> {
+fn load_client_config(conf: &SourceConfig, name: &str) -> ClientConfig {
+    match conf {
+        SourceConfig::Kubeconfig => {
+            ClientConfig::from_kubeconfig(name).expect("Couldn't load client config")
+        }
+        SourceConfig::Serviceaccount => {
+            ClientConfig::from_service_account(name).expect("Couldn't load client config")
+        }
+    }
+}
+
+fn load_api(conf: &SourceConfig, api_proxy: &str) -> Api {
+    match conf {
+        SourceConfig::Kubeconfig => Api::proxy(api_proxy),
+        SourceConfig::Serviceaccount => Api::in_cluster().expect("Couldn't load API client"),
+    }
+}
+
+fn handle_sync(
+    api: &Api,
+    service_monitors: bool,
+    request: &SyncRequest,
+) -> Result<SyncResponse, Error> {
+    let started = Instant::now();
+
+    let response = sync(api, service_monitors, request);
+
+    metrics::record_sync(
+        OPERATOR_NAME,
+        request,
+        started.elapsed(),
+        response.as_ref().ok(),
+    );
+
+    response
+}
+
+fn sync(api: &Api, service_monitors: bool, request: &SyncRequest) -> Result<SyncResponse, Error> {
     let crd: model::Noria = request.deserialize_parent()?;
 
+    // the error status reports the problems, the existing children are kept
+    let errors = validate::validate(&crd);
+    if !errors.is_empty() {
+        return Err(Box::new(error::ValidationError { errors }));
+    }
+
+    let references = read_references(api, &crd)?;
+
+    let mut children = create_children(&crd, Some(request), &references)?;
+
+    // without the Prometheus operator only the metrics Services are created
+    if !service_monitors {
+        children.retain(|child| child["kind"] != "ServiceMonitor");
+    }
+
+    update::plan(
+        request,
+        &crd.metadata.name,
+        &crd.metadata.namespace,
+        &mut children,
+    );
+
+    let failed_recipes = children
+        .iter()
+        .filter(|child| recipe::failed(request, child))
+        .filter_map(|child| child["metadata"]["labels"]["noria-operator.io/deployment"].as_str())
+        .map(String::from)
+        .collect::<Vec<String>>();
+    children.retain(|child| !recipe::failed(request, child));
+
+    let status = status::create_status(request, &children, &failed_recipes);
+
+    // resync while rolling out, so that a halted rollout is noticed, and while
+    // ConfigMaps or Secrets are referenced, since they are not watched
+    let has_references = crd.spec.deployments.iter().any(|d| d.recipe.is_some())
+        || !referenced_secrets(&crd).is_empty();
+    let resync = if status["phase"] == "Running" && !has_references {
+        None
+    } else {
+        Some(Duration::from_secs(RESYNC_INTERVAL_SECONDS))
+    };
+
+    Ok(SyncResponse {
+        status,
+        children,
+        resync,
+    })
+}
+
+// ConfigMaps and Secrets referenced by a Noria are not its children, so they are read on every sync
+#[derive(Default)]
+struct References {
+    // recipe hashes by deployment id, a recipe whose ConfigMap is missing is left out
+    recipes: HashMap<String, String>,
+    // Secret checksums by Secret name
+    checksums: HashMap<String, String>,
+}
+
+// Secrets mounted by the pods of enabled components
+fn referenced_secrets(crd: &model::Noria) -> Vec<String> {
+    let noria_name = crd.metadata.name.as_str();
+
+    let mut secrets = vec![];
+
+    secrets.extend(zookeeper::tls_secret(noria_name, &crd.spec.zookeeper));
+
+    for deployment in &crd.spec.deployments {
+        if !mysql::enabled(&deployment.noria_mysql) {
+            continue;
+        }
+        if let Some(NoriaMysqlConfig {
+            credentials: Some(credentials),
+            ..
+        }) = &deployment.noria_mysql
+        {
+            secrets.push(credentials.secret.clone());
+        }
+        secrets.extend(mysql::tls_secret(
+            noria_name,
+            &deployment.noria_mysql,
+            &deployment.id,
+        ));
+    }
+
+    if ui::enabled(&crd.spec.noria_ui) {
+        secrets.extend(ui::auth_secret(&crd.spec.noria_ui).map(String::from));
+    }
+
+    secrets.sort();
+    secrets.dedup();
+    secrets
+}
+
+fn read_references(api: &Api, crd: &model::Noria) -> Result<References, Error> {
+    let mut references = References::default();
+
+    for deployment in &crd.spec.deployments {
+        if let Some(recipe) = &deployment.recipe {
+            match recipe::hash(api, &crd.metadata.namespace, recipe)? {
+                Some(hash) => {
+                    references.recipes.insert(deployment.id.clone(), hash);
+                }
+                None => warn!(
+                    "Recipe of deployment {} not found in ConfigMap {}",
+                    deployment.id, recipe.config_map
+                ),
+            }
+        }
+    }
+
+    references.checksums = checksum::read(api, &crd.metadata.namespace, &referenced_secrets(crd))?;
+
+    Ok(references)
+}
+
+// The request carries the children currently in the cluster. It is absent when rendering
+// offline, as are the references.
+fn create_children(
+    crd: &model::Noria,
+    request: Option<&SyncRequest>,
+    references: &References,
+) -> Result<Vec<Value>, Error> {
+    let noria_name = crd.metadata.name.as_str();
     let noria_namespace = crd.metadata.namespace.as_str();
 
     let mut children = vec![];
@@ -108,42 +471,171 @@ fn handle_sync(request: &SyncRequest) -> Result<SyncResponse, Error> {
     // --
     // Zookeeper
 
-    children.append(
-        &mut zookeeper::create_config(&crd.spec.zookeeper, &mut extend_properties)
+    if zookeeper::external_config(&crd.spec.zookeeper).is_none() {
+        let observed = request.and_then(|r| zookeeper::observe(r, noria_name, noria_namespace));
+
+        let zookeeper_secret = zookeeper::tls_secret(noria_name, &crd.spec.zookeeper);
+        let secret_checksums = checksum::pod_annotations(
+            &references.checksums,
+            &zookeeper_secret
+                .as_deref()
+                .map(|secret| ("tls", secret))
+                .into_iter()
+                .collect::<Vec<(&str, &str)>>(),
+        );
+
+        children.append(
+            &mut zookeeper::create_config(
+                noria_name,
+                &crd.spec.zookeeper,
+                &observed,
+                &mut extend_properties,
+                secret_checksums,
+                crd.spec.monitoring.as_ref(),
+            )?
             .children(noria_namespace),
-    );
+        );
+    }
+
+    let zookeeper_address = zookeeper::address(noria_name, &crd.spec.zookeeper);
+
+    let rollout_paused = crd.metadata.annotation(server::PAUSE_ROLLOUT_ANNOTATION) == Some("true");
 
     // --
     // NoriaServer, NoriaMysql per deployment
 
-    for deployment in crd.spec.deployments {
+    for deployment in &crd.spec.deployments {
         if deployment.id.contains('-') {
-            return Err(Box::new(error::DeploymentIdDashError { id: deployment.id }));
+            return Err(Box::new(error::DeploymentIdDashError {
+                id: deployment.id.clone(),
+            }));
+        }
+
+        if server::enabled(&deployment.noria_server) {
+            let server_config = server::create_config(
+                noria_name,
+                &deployment.noria_server,
+                &deployment.id,
+                &zookeeper_address,
+                rollout_paused,
+                deployment.restore.as_ref(),
+                crd.spec.monitoring.as_ref(),
+            );
+
+            let restore_pending = match (&deployment.restore, request) {
+                (Some(restore), Some(request)) => restore::pending(
+                    request,
+                    restore,
+                    noria_name,
+                    noria_namespace,
+                    &deployment.id,
+                ),
+                _ => false,
+            };
+
+            if let Some(restore) = &deployment.restore {
+                children.append(
+                    &mut restore::create_config(&server_config, restore).children(noria_namespace),
+                );
+            }
+
+            // the StatefulSet adopts the restored claims, so they come first
+            if !restore_pending {
+                children.append(&mut server_config.children(noria_namespace));
+            }
         }
 
+        // the recipe is applied through noria-mysql
+        if !mysql::enabled(&deployment.noria_mysql) {
+            continue;
+        }
+
+        let credentials = match &deployment.noria_mysql {
+            Some(NoriaMysqlConfig {
+                credentials: Some(c),
+                ..
+            }) => Some(c),
+            _ => None,
+        };
+
+        let tls_secret = mysql::tls_secret(noria_name, &deployment.noria_mysql, &deployment.id);
+
+        if tls_secret.is_some() && credentials.is_none() {
+            return Err(Box::new(error::MysqlTlsCredentialsError {
+                id: deployment.id.clone(),
+            }));
+        }
+
+        if crd.spec.monitoring.is_some() && credentials.is_none() {
+            info!(
+                "Not monitoring noria-mysql of deployment {}, its metrics come from the ProxySQL that only runs with credentials",
+                deployment.id
+            );
+        }
+
+        let mysql_secrets = credentials
+            .map(|c| ("credentials", c.secret.as_str()))
+            .into_iter()
+            .chain(tls_secret.as_deref().map(|secret| ("tls", secret)))
+            .collect::<Vec<(&str, &str)>>();
+        let secret_checksums = checksum::pod_annotations(&references.checksums, &mysql_secrets);
+
         children.append(
-            &mut server::create_config(&deployment.noria_server, &deployment.id)
-                .children(noria_namespace),
+            &mut mysql::create_config(
+                noria_name,
+                &deployment.noria_mysql,
+                &deployment.id,
+                &zookeeper_address,
+                secret_checksums,
+                crd.spec.monitoring.as_ref(),
+            )
+            .children(noria_namespace),
         );
 
-        children.append(
-            &mut mysql::create_config(&deployment.noria_mysql, &deployment.id)
+        if let (Some(recipe), Some(hash)) =
+            (&deployment.recipe, references.recipes.get(&deployment.id))
+        {
+            children.append(
+                &mut recipe::create_config(
+                    noria_name,
+                    recipe,
+                    hash,
+                    credentials,
+                    tls_secret.is_some(),
+                    &deployment.id,
+                )
                 .children(noria_namespace),
-        );
+            );
+        }
     }
 
     // --
-    // NoriaUI
+    // NoriaUI per deployment
 
-    children.append(&mut ui::create_config(&crd.spec.noria_ui).children(noria_namespace));
+    if ui::enabled(&crd.spec.noria_ui) {
+        let ui_secrets = ui::auth_secret(&crd.spec.noria_ui)
+            .map(|secret| ("auth", secret))
+            .into_iter()
+            .collect::<Vec<(&str, &str)>>();
+        let secret_checksums = checksum::pod_annotations(&references.checksums, &ui_secrets);
 
-    let status = json!({
-        "message": "Sync complete",
-    });
+        children.append(
+            &mut ui::create_config(
+                noria_name,
+                &crd.spec.noria_ui,
+                // the UI follows the controller of each deployment running noria-server
+                crd.spec
+                    .deployments
+                    .iter()
+                    .filter(|d| server::enabled(&d.noria_server))
+                    .map(|d| d.id.as_str())
+                    .collect(),
+                &zookeeper_address,
+                secret_checksums,
+            )
+            .children(noria_namespace),
+        );
+    }
 
-    Ok(SyncResponse {
-        status,
-        children,
-        resync: None,
-    })
+    Ok(children)
 }
@@ -0,0 +1,274 @@
+use super::error;
+
+use roperator::prelude::*;
+use roperator::serde_json::Value;
+
+use lazy_static::lazy_static;
+
+use tiny_http::{Header, Method, Response, Server};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::*;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+// Upper bounds of the reconcile duration buckets, in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Parents are keyed by operator, namespace and name
+type ParentKey = (&'static str, String, String);
+
+struct Metrics {
+    // by operator and result
+    reconciles: BTreeMap<(&'static str, &'static str), u64>,
+    // by operator and error kind
+    errors: BTreeMap<(&'static str, &'static str), u64>,
+    durations: BTreeMap<&'static str, Histogram>,
+    // children of the last successful sync by kind, for every parent seen
+    children: BTreeMap<ParentKey, BTreeMap<String, u64>>,
+}
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+        reconciles: BTreeMap::new(),
+        errors: BTreeMap::new(),
+        durations: BTreeMap::new(),
+        children: BTreeMap::new(),
+    });
+}
+
+fn parent_key(operator: &'static str, request: &SyncRequest) -> ParentKey {
+    let field = |path: &str| {
+        request
+            .parent
+            .pointer(path)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    (
+        operator,
+        field("/metadata/namespace"),
+        field("/metadata/name"),
+    )
+}
+
+// A parent that is being deleted is no longer managed
+fn update_parent(metrics: &mut Metrics, key: ParentKey, request: &SyncRequest) {
+    if request
+        .parent
+        .pointer("/metadata/deletionTimestamp")
+        .is_some()
+    {
+        metrics.children.remove(&key);
+    } else {
+        metrics.children.entry(key).or_default();
+    }
+}
+
+/// Records a sync of `operator`, `response` is None when it failed. The
+/// failure itself is counted by `record_error`.
+pub fn record_sync(
+    operator: &'static str,
+    request: &SyncRequest,
+    duration: Duration,
+    response: Option<&SyncResponse>,
+) {
+    let mut metrics = match METRICS.lock() {
+        Ok(metrics) => metrics,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    metrics
+        .durations
+        .entry(operator)
+        .or_default()
+        .observe(duration.as_secs_f64());
+
+    let response = match response {
+        Some(response) => response,
+        None => return,
+    };
+
+    *metrics.reconciles.entry((operator, "success")).or_default() += 1;
+
+    let key = parent_key(operator, request);
+    update_parent(&mut metrics, key.clone(), request);
+
+    if let Some(children) = metrics.children.get_mut(&key) {
+        children.clear();
+        for child in &response.children {
+            let kind = child["kind"].as_str().unwrap_or_default().to_string();
+            *children.entry(kind).or_default() += 1;
+        }
+    }
+}
+
+/// Records a failed sync of `operator`, labeled with the kind of error
+pub fn record_error(operator: &'static str, request: &SyncRequest, err: &Error) {
+    let mut metrics = match METRICS.lock() {
+        Ok(metrics) => metrics,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    *metrics.reconciles.entry((operator, "error")).or_default() += 1;
+    *metrics
+        .errors
+        .entry((operator, error::kind(&**err)))
+        .or_default() += 1;
+
+    update_parent(&mut metrics, parent_key(operator, request), request);
+}
+
+// Prometheus text exposition format
+fn render() -> String {
+    let metrics = match METRICS.lock() {
+        Ok(metrics) => metrics,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    let mut out = String::new();
+
+    // writing to a String cannot fail
+    let _ = writeln!(
+        out,
+        "# HELP noria_operator_reconciles_total Syncs handled, by result.\n\
+         # TYPE noria_operator_reconciles_total counter"
+    );
+    for ((operator, result), count) in &metrics.reconciles {
+        let _ = writeln!(
+            out,
+            "noria_operator_reconciles_total{{operator=\"{}\",result=\"{}\"}} {}",
+            operator, result, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP noria_operator_reconcile_errors_total Failed syncs, by kind of error.\n\
+         # TYPE noria_operator_reconcile_errors_total counter"
+    );
+    for ((operator, kind), count) in &metrics.errors {
+        let _ = writeln!(
+            out,
+            "noria_operator_reconcile_errors_total{{operator=\"{}\",kind=\"{}\"}} {}",
+            operator, kind, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP noria_operator_reconcile_duration_seconds Time taken by a sync.\n\
+         # TYPE noria_operator_reconcile_duration_seconds histogram"
+    );
+    for (operator, histogram) in &metrics.durations {
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            let _ = writeln!(
+                out,
+                "noria_operator_reconcile_duration_seconds_bucket{{operator=\"{}\",le=\"{}\"}} {}",
+                operator, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "noria_operator_reconcile_duration_seconds_bucket{{operator=\"{}\",le=\"+Inf\"}} {}\n\
+             noria_operator_reconcile_duration_seconds_sum{{operator=\"{}\"}} {}\n\
+             noria_operator_reconcile_duration_seconds_count{{operator=\"{}\"}} {}",
+            operator, histogram.count, operator, histogram.sum, operator, histogram.count
+        );
+    }
+
+    let mut managed = BTreeMap::new();
+    for (operator, _, _) in metrics.children.keys() {
+        *managed.entry(operator).or_insert(0) += 1;
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP noria_operator_managed_objects Parent resources managed by the operator.\n\
+         # TYPE noria_operator_managed_objects gauge"
+    );
+    for (operator, count) in managed {
+        let _ = writeln!(
+            out,
+            "noria_operator_managed_objects{{operator=\"{}\"}} {}",
+            operator, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP noria_operator_children Children of the last successful sync, by kind.\n\
+         # TYPE noria_operator_children gauge"
+    );
+    for ((operator, namespace, name), children) in &metrics.children {
+        for (kind, count) in children {
+            let _ = writeln!(
+                out,
+                "noria_operator_children{{operator=\"{}\",namespace=\"{}\",name=\"{}\",kind=\"{}\"}} {}",
+                operator, namespace, name, kind, count
+            );
+        }
+    }
+
+    out
+}
+
+/// Serves the metrics over HTTP, never returns unless the server cannot be
+/// started
+pub fn run(port: u16) -> Error {
+    let server = match Server::http(("0.0.0.0", port)) {
+        Ok(server) => server,
+        Err(err) => return err,
+    };
+
+    info!("Metrics listening on port {}", port);
+
+    for request in server.incoming_requests() {
+        let path = request.url().split('?').next().unwrap_or_default();
+
+        let response = if *request.method() != Method::Get || path != METRICS_PATH {
+            Response::from_string("Not found").with_status_code(404)
+        } else {
+            Response::from_string(render()).with_header(
+                "Content-Type: text/plain; version=0.0.4"
+                    .parse::<Header>()
+                    .expect("valid header"),
+            )
+        };
+
+        if let Err(err) = request.respond(response) {
+            warn!("Failed to respond to metrics request: {}", err);
+        }
+    }
+
+    Box::new(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "Metrics server stopped",
+    ))
+}
@@ -1,4 +1,8 @@
 use roperator::prelude::*;
+use roperator::serde_json::{self, json, Value};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
 use std::collections::HashMap;
 
 pub static PARENT_TYPE_NORIA_CLUSTER: &K8sType = &K8sType {
@@ -7,6 +11,84 @@ pub static PARENT_TYPE_NORIA_CLUSTER: &K8sType = &K8sType {
     plural_kind: "norias",
 };
 
+pub static PARENT_TYPE_NORIA_BACKUP: &K8sType = &K8sType {
+    api_version: "noria-operator.io/v1alpha1",
+    kind: "NoriaBackup",
+    plural_kind: "noriabackups",
+};
+
+pub static CHILD_TYPE_JOB: &K8sType = &K8sType {
+    api_version: "batch/v1",
+    kind: "Job",
+    plural_kind: "jobs",
+};
+
+pub static CHILD_TYPE_CRON_JOB: &K8sType = &K8sType {
+    api_version: "batch/v1",
+    kind: "CronJob",
+    plural_kind: "cronjobs",
+};
+
+pub static CHILD_TYPE_HORIZONTAL_POD_AUTOSCALER: &K8sType = &K8sType {
+    api_version: "autoscaling/v2",
+    kind: "HorizontalPodAutoscaler",
+    plural_kind: "horizontalpodautoscalers",
+};
+
+pub static CHILD_TYPE_POD_DISRUPTION_BUDGET: &K8sType = &K8sType {
+    api_version: "policy/v1",
+    kind: "PodDisruptionBudget",
+    plural_kind: "poddisruptionbudgets",
+};
+
+pub static CHILD_TYPE_INGRESS: &K8sType = &K8sType {
+    api_version: "networking.k8s.io/v1",
+    kind: "Ingress",
+    plural_kind: "ingresses",
+};
+
+pub static CHILD_TYPE_CERTIFICATE: &K8sType = &K8sType {
+    api_version: "cert-manager.io/v1",
+    kind: "Certificate",
+    plural_kind: "certificates",
+};
+
+pub static CHILD_TYPE_VOLUME_SNAPSHOT: &K8sType = &K8sType {
+    api_version: "snapshot.storage.k8s.io/v1",
+    kind: "VolumeSnapshot",
+    plural_kind: "volumesnapshots",
+};
+
+pub static CHILD_TYPE_PERSISTENT_VOLUME_CLAIM: &K8sType = &K8sType {
+    api_version: "v1",
+    kind: "PersistentVolumeClaim",
+    plural_kind: "persistentvolumeclaims",
+};
+
+pub static CHILD_TYPE_SERVICE_MONITOR: &K8sType = &K8sType {
+    api_version: "monitoring.coreos.com/v1",
+    kind: "ServiceMonitor",
+    plural_kind: "servicemonitors",
+};
+
+pub static CHILD_TYPE_SERVICE_ACCOUNT: &K8sType = &K8sType {
+    api_version: "v1",
+    kind: "ServiceAccount",
+    plural_kind: "serviceaccounts",
+};
+
+pub static CHILD_TYPE_ROLE: &K8sType = &K8sType {
+    api_version: "rbac.authorization.k8s.io/v1",
+    kind: "Role",
+    plural_kind: "roles",
+};
+
+pub static CHILD_TYPE_ROLE_BINDING: &K8sType = &K8sType {
+    api_version: "rbac.authorization.k8s.io/v1",
+    kind: "RoleBinding",
+    plural_kind: "rolebindings",
+};
+
 #[derive(Serialize, Deserialize)]
 pub struct Noria {
     pub metadata: Metadata,
@@ -17,20 +99,86 @@ pub struct Noria {
 pub struct Metadata {
     pub name: String,
     pub namespace: String,
+    pub annotations: Option<HashMap<String, String>>,
 }
 
-#[derive(Serialize, Deserialize)]
+impl Metadata {
+    pub fn annotation(&self, key: &str) -> Option<&str> {
+        self.annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(key))
+            .map(String::as_str)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct NoriaSpec {
     pub deployments: Vec<Deployment>,
     pub zookeeper: Option<ZookeeperConfig>,
     pub noria_ui: Option<NoriaUiConfig>,
+    pub monitoring: Option<MonitoringConfig>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Deployment {
+    #[schemars(regex(pattern = r"^[a-z0-9]+$"))]
     pub id: String,
     pub noria_server: Option<NoriaServerConfig>,
     pub noria_mysql: Option<NoriaMysqlConfig>,
+    pub recipe: Option<RecipeConfig>,
+    pub restore: Option<RestoreConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RestoreConfig {
+    #[schemars(length(min = 1))]
+    pub backup: String,
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NoriaBackup {
+    pub metadata: Metadata,
+    pub spec: NoriaBackupSpec,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct NoriaBackupSpec {
+    #[schemars(length(min = 1))]
+    pub noria: String,
+    #[schemars(regex(pattern = r"^[a-z0-9]+$"))]
+    pub deployment: String,
+    pub volume_snapshot: Option<VolumeSnapshotConfig>,
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct VolumeSnapshotConfig {
+    #[schemars(length(min = 1))]
+    pub class_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct S3Config {
+    #[schemars(regex(pattern = r"^https?://"))]
+    pub endpoint: String,
+    #[schemars(length(min = 1))]
+    pub bucket: String,
+    // object keys are appended to the prefix as is
+    #[schemars(regex(pattern = r"(^$|/$)"))]
+    pub prefix: Option<String>,
+    #[schemars(length(min = 1))]
+    pub region: Option<String>,
+    #[schemars(length(min = 1))]
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RecipeConfig {
+    #[schemars(length(min = 1))]
+    pub config_map: String,
+    #[schemars(length(min = 1))]
+    pub key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -42,32 +190,325 @@ trait Opt {}
 
 impl Opt for ZookeeperConfig {}
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct ZookeeperConfig {
     pub version: Option<String>,
     pub max_heap: Option<u64>,
+    #[schemars(range(min = 1))]
+    pub memory_multiplier: Option<f64>,
+    pub resources: Option<ResourcesConfig>,
+    pub scheduling: Option<SchedulingConfig>,
     pub storage_size: Option<u64>,
+    pub storage: Option<StorageConfig>,
     pub replicas: Option<usize>,
     pub additional_properties: Option<HashMap<String, String>>,
+    pub external: Option<ExternalZookeeperConfig>,
+    pub tls: Option<TlsConfig>,
+    pub backup: Option<ZookeeperBackupConfig>,
+    pub restore: Option<ZookeeperRestoreConfig>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ZookeeperBackupConfig {
+    #[schemars(length(min = 1))]
+    pub schedule: String,
+    #[schemars(range(min = 1))]
+    pub retention: Option<usize>,
+    #[schemars(length(min = 1))]
+    pub claim_name: Option<String>,
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ZookeeperRestoreConfig {
+    #[schemars(length(min = 1))]
+    pub snapshot: String,
+    #[schemars(length(min = 1))]
+    pub claim_name: Option<String>,
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ExternalZookeeperConfig {
+    // Both end up in shell commands, so they are limited to host:port lists
+    // and plain paths
+    #[schemars(regex(pattern = r"^[A-Za-z0-9.-]+:[0-9]+(,[A-Za-z0-9.-]+:[0-9]+)*$"))]
+    pub connect_string: String,
+    #[schemars(regex(pattern = r"^(/[A-Za-z0-9_.-]+)+$"))]
+    pub chroot: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct StorageConfig {
+    #[schemars(length(min = 1))]
+    pub class_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "access_modes")]
+    pub access_modes: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "retention_policy")]
+    pub retention_policy: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct NoriaServerConfig {
+    pub enabled: Option<bool>,
     pub version: Option<String>,
     pub max_heap: Option<u64>,
+    #[schemars(range(min = 1))]
+    pub memory_multiplier: Option<f64>,
+    pub resources: Option<ResourcesConfig>,
+    pub scheduling: Option<SchedulingConfig>,
     pub storage_size: Option<u64>,
+    pub storage: Option<StorageConfig>,
     pub replicas: Option<usize>,
+    /// Pods replaced at once during a rollout. Needs the alpha
+    /// MaxUnavailableStatefulSet feature gate, without which the API server
+    /// drops the setting and pods are replaced one at a time.
+    #[schemars(range(min = 1))]
+    pub max_unavailable: Option<usize>,
+    pub disruption_budget: Option<DisruptionBudgetConfig>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct NoriaMysqlConfig {
+    pub enabled: Option<bool>,
     pub version: Option<String>,
     pub max_heap: Option<u64>,
+    #[schemars(range(min = 1))]
+    pub memory_multiplier: Option<f64>,
+    pub resources: Option<ResourcesConfig>,
+    pub scheduling: Option<SchedulingConfig>,
     pub replicas: Option<usize>,
+    pub credentials: Option<MysqlCredentialsConfig>,
+    pub tls: Option<TlsConfig>,
+    pub service: Option<MysqlServiceConfig>,
+    pub autoscaling: Option<MysqlAutoscalingConfig>,
+    pub disruption_budget: Option<DisruptionBudgetConfig>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct DisruptionBudgetConfig {
+    pub enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "pod_count")]
+    pub min_available: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "pod_count")]
+    pub max_unavailable: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct MysqlAutoscalingConfig {
+    #[schemars(range(min = 1))]
+    pub min_replicas: Option<usize>,
+    #[schemars(range(min = 1))]
+    pub max_replicas: usize,
+    #[schemars(range(min = 1))]
+    pub target_cpu_utilization: Option<u32>,
+    pub metric: Option<CustomMetricConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct CustomMetricConfig {
+    #[schemars(length(min = 1))]
+    pub name: String,
+    #[schemars(length(min = 1))]
+    pub target_average_value: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct MysqlServiceConfig {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "service_type")]
+    pub service_type: Option<String>,
+    pub annotations: Option<HashMap<String, String>>,
+    pub load_balancer_source_ranges: Option<Vec<String>>,
+    #[schemars(range(min = 1, max = 65535))]
+    pub node_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "external_traffic_policy")]
+    pub external_traffic_policy: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct MysqlCredentialsConfig {
+    #[schemars(length(min = 1))]
+    pub secret: String,
+    #[schemars(length(min = 1))]
+    pub username_key: Option<String>,
+    #[schemars(length(min = 1))]
+    pub password_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ResourcesConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "quantities")]
+    pub requests: Option<HashMap<String, Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "quantities")]
+    pub limits: Option<HashMap<String, Value>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SchedulingConfig {
+    pub node_selector: Option<HashMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "objects")]
+    pub tolerations: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "object")]
+    pub affinity: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "objects")]
+    pub topology_spread_constraints: Option<Vec<Value>>,
+    #[schemars(length(min = 1))]
+    pub priority_class_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct TlsConfig {
+    #[schemars(length(min = 1))]
+    pub secret: Option<String>,
+    pub issuer: Option<IssuerConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct IssuerConfig {
+    #[schemars(length(min = 1))]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "issuer_kind")]
+    pub kind: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct MonitoringConfig {
+    #[schemars(regex(pattern = r"^[0-9]+(ms|s|m|h)$"))]
+    pub interval: Option<String>,
+    pub labels: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct NoriaUiConfig {
+    pub enabled: Option<bool>,
     pub version: Option<String>,
     pub max_heap: Option<u64>,
+    #[schemars(range(min = 1))]
+    pub memory_multiplier: Option<f64>,
+    pub resources: Option<ResourcesConfig>,
+    pub scheduling: Option<SchedulingConfig>,
+    pub ingress: Option<UiIngressConfig>,
+    pub auth: Option<UiAuthConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct UiIngressConfig {
+    #[schemars(length(min = 1))]
+    pub host: String,
+    #[schemars(regex(pattern = r"^/"))]
+    pub path: Option<String>,
+    #[schemars(length(min = 1))]
+    pub class_name: Option<String>,
+    #[schemars(length(min = 1))]
+    pub tls_secret: Option<String>,
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct UiAuthConfig {
+    pub oauth2: Option<OAuth2ProxyConfig>,
+    pub basic: Option<BasicAuthConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct OAuth2ProxyConfig {
+    #[schemars(length(min = 1))]
+    pub provider: String,
+    #[schemars(length(min = 1))]
+    pub secret: String,
+    pub email_domains: Option<Vec<String>>,
+    pub args: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct BasicAuthConfig {
+    #[schemars(length(min = 1))]
+    pub secret: String,
+    #[schemars(length(min = 1))]
+    pub key: Option<String>,
+}
+
+// Schemas of fields whose Rust types are looser than what Kubernetes accepts
+
+fn schema(value: Value) -> Schema {
+    serde_json::from_value(value).expect("Invalid schema")
+}
+
+fn string_enum(values: &[&str]) -> Schema {
+    schema(json!({ "type": "string", "enum": values }))
+}
+
+fn access_modes(_: &mut SchemaGenerator) -> Schema {
+    schema(json!({
+        "type": "array",
+        "items": {
+            "type": "string",
+            "enum": [
+                "ReadWriteOnce",
+                "ReadOnlyMany",
+                "ReadWriteMany",
+                "ReadWriteOncePod",
+            ],
+        },
+        "minItems": 1,
+    }))
+}
+
+fn retention_policy(_: &mut SchemaGenerator) -> Schema {
+    string_enum(&["Retain", "Delete"])
+}
+
+fn service_type(_: &mut SchemaGenerator) -> Schema {
+    string_enum(&["ClusterIP", "NodePort", "LoadBalancer"])
+}
+
+fn external_traffic_policy(_: &mut SchemaGenerator) -> Schema {
+    string_enum(&["Cluster", "Local"])
+}
+
+fn issuer_kind(_: &mut SchemaGenerator) -> Schema {
+    string_enum(&["Issuer", "ClusterIssuer"])
+}
+
+// a number of pods, or a percentage such as "50%". Structural schemas allow
+// no constraints on int-or-string fields, validate.rs checks the values.
+fn pod_count(_: &mut SchemaGenerator) -> Schema {
+    schema(json!({
+        "anyOf": [{ "type": "integer" }, { "type": "string" }],
+        "x-kubernetes-int-or-string": true,
+    }))
+}
+
+fn quantities(_: &mut SchemaGenerator) -> Schema {
+    schema(json!({
+        "type": "object",
+        "additionalProperties": {
+            "anyOf": [{ "type": "integer" }, { "type": "string" }],
+            "x-kubernetes-int-or-string": true,
+        },
+    }))
+}
+
+// Passed through to the pod spec as is, the API server validates them
+fn object(_: &mut SchemaGenerator) -> Schema {
+    schema(json!({
+        "type": "object",
+        "x-kubernetes-preserve-unknown-fields": true,
+    }))
+}
+
+fn objects(gen: &mut SchemaGenerator) -> Schema {
+    schema(json!({ "type": "array", "items": object(gen) }))
 }
@@ -0,0 +1,287 @@
+use super::MonitoringConfig;
+use super::{JMX_EXPORTER_IMAGE, NORIA_IMAGE};
+
+use roperator::serde_json::{json, Value};
+
+const DEFAULT_SCRAPE_INTERVAL: &str = "30s";
+
+/// Port of every exporter, named `metrics` in the pods and Services
+pub const METRICS_PORT: u16 = 9102;
+
+// ZooKeeper's JMX server only answers the exporter next to it
+pub const ZOOKEEPER_JMX_PORT: u16 = 9999;
+const JMX_EXPORTER_CONFIG_PATH: &str = "/etc/jmx-exporter";
+
+// Mirrors the ZooKeeper rules shipped with the JMX exporter
+const ZOOKEEPER_JMX_RULES: &str = r#"rules:
+- pattern: "org.apache.ZooKeeperService<name0=StandaloneServer_port(\\d+)><>(\\w+)"
+  name: "zookeeper_$2"
+  type: GAUGE
+- pattern: "org.apache.ZooKeeperService<name0=ReplicatedServer_id(\\d+)><>(\\w+)"
+  name: "zookeeper_$2"
+  type: GAUGE
+- pattern: "org.apache.ZooKeeperService<name0=ReplicatedServer_id(\\d+), name1=replica.(\\d+)><>(\\w+)"
+  name: "zookeeper_$3"
+  type: GAUGE
+  labels:
+    replicaId: "$2"
+- pattern: "org.apache.ZooKeeperService<name0=ReplicatedServer_id(\\d+), name1=replica.(\\d+), name2=(\\w+)><>(Packets\\w+)"
+  name: "zookeeper_$4"
+  type: COUNTER
+  labels:
+    replicaId: "$2"
+    memberType: "$3"
+- pattern: "org.apache.ZooKeeperService<name0=ReplicatedServer_id(\\d+), name1=replica.(\\d+), name2=(\\w+)><>(\\w+)"
+  name: "zookeeper_$4"
+  type: GAUGE
+  labels:
+    replicaId: "$2"
+    memberType: "$3"
+- pattern: "org.apache.ZooKeeperService<name0=ReplicatedServer_id(\\d+), name1=replica.(\\d+), name2=(\\w+), name3=(\\w+)><>(\\w+)"
+  name: "zookeeper_$4_$5"
+  type: GAUGE
+  labels:
+    replicaId: "$2"
+    memberType: "$3"
+"#;
+
+// Turns the controller's graph and statistics into Prometheus metrics. Only
+// the leader's controller answers, the other pods report that they don't lead.
+const NORIA_EXPORTER_SCRIPT: &str = r##"
+import json, os, re, urllib.request
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+API = "http://%s:6033" % os.environ["NODE_IP"]
+
+def fetch(path, data=None):
+    return urllib.request.urlopen(API + path, data=data, timeout=5).read()
+
+def pairs(value):
+    # maps keyed by tuples arrive as lists of pairs
+    return value.items() if isinstance(value, dict) else value
+
+def escape(value):
+    return str(value).replace("\\", "\\\\").replace('"', '\\"')
+
+def samples(out, prefix, labels, stats):
+    for field, value in pairs(stats if isinstance(stats, dict) else {}):
+        if isinstance(value, bool):
+            value = int(value)
+        if isinstance(value, (int, float)):
+            label = ",".join('%s="%s"' % (k, escape(v)) for k, v in labels)
+            out.append("%s_%s{%s} %s" % (prefix, field, label, value))
+
+def collect():
+    try:
+        graph = fetch("/graph").decode()
+        workers = json.loads(fetch("/healthy_workers"))
+        stats = json.loads(fetch("/get_statistics", b""))
+    except Exception:
+        return "noria_controller_leader 0\n"
+
+    out = [
+        "noria_controller_leader 1",
+        "noria_healthy_workers %d" % len(workers),
+        "noria_graph_nodes %d" % len(re.findall(r"^\s*n\d+ \[", graph, re.M)),
+    ]
+    for key, value in pairs(stats.get("domains", {})):
+        index, shard = key if isinstance(key, list) else (key, 0)
+        domain, nodes = value if isinstance(value, list) else (value, {})
+        labels = [("domain", index), ("shard", shard)]
+        samples(out, "noria_domain", labels, domain)
+        for node, node_stats in pairs(nodes):
+            samples(out, "noria_node", labels + [("node", node)], node_stats)
+    return "\n".join(out) + "\n"
+
+class Handler(BaseHTTPRequestHandler):
+    def do_GET(self):
+        if self.path != "/metrics":
+            self.send_error(404)
+            return
+        body = collect().encode()
+        self.send_response(200)
+        self.send_header("Content-Type", "text/plain; version=0.0.4")
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+HTTPServer(("", int(os.environ["METRICS_PORT"])), Handler).serve_forever()
+"##;
+
+/// ConfigMap with the JMX exporter's rules for ZooKeeper
+pub fn zookeeper_exporter_config(name: &str, namespace: &str) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": {
+            "name": name,
+            "namespace": namespace,
+        },
+        "data": {
+            "config.yaml": format!(
+                "hostPort: 127.0.0.1:{}\nlowercaseOutputName: true\n{}",
+                ZOOKEEPER_JMX_PORT, ZOOKEEPER_JMX_RULES
+            )
+        }
+    })
+}
+
+/// JVM options opening ZooKeeper's JMX server on the loopback interface
+pub fn zookeeper_jmx_options() -> String {
+    format!(
+        "-Dcom.sun.management.jmxremote \
+         -Dcom.sun.management.jmxremote.authenticate=false \
+         -Dcom.sun.management.jmxremote.ssl=false \
+         -Dcom.sun.management.jmxremote.host=127.0.0.1 \
+         -Dcom.sun.management.jmxremote.port={port} \
+         -Dcom.sun.management.jmxremote.rmi.port={port} \
+         -Djava.rmi.server.hostname=127.0.0.1",
+        port = ZOOKEEPER_JMX_PORT
+    )
+}
+
+/// JMX exporter sidecar of a ZooKeeper member
+pub fn zookeeper_exporter() -> Value {
+    json!({
+        "name": "jmx-exporter",
+        "image": JMX_EXPORTER_IMAGE,
+        "args": [
+            METRICS_PORT.to_string(),
+            format!("{}/config.yaml", JMX_EXPORTER_CONFIG_PATH)
+        ],
+        "ports": [{
+            "containerPort": METRICS_PORT,
+            "name": "metrics",
+            "protocol": "TCP",
+        }],
+        "volumeMounts": [{
+            "mountPath": JMX_EXPORTER_CONFIG_PATH,
+            "name": "jmx-exporter",
+            "readOnly": true
+        }]
+    })
+}
+
+/// Volume with the JMX exporter's rules
+pub fn zookeeper_exporter_volume(config_map_name: &str) -> Value {
+    json!({
+        "name": "jmx-exporter",
+        "configMap": {
+            "name": config_map_name
+        }
+    })
+}
+
+/// Exporter sidecar of a noria-server pod
+pub fn server_exporter(version: &str) -> Value {
+    json!({
+        "name": "exporter",
+        "image": format!("{}:{}", NORIA_IMAGE, version),
+        "command": ["python3", "-c", NORIA_EXPORTER_SCRIPT],
+        "env": [{
+            "name": "METRICS_PORT",
+            "value": METRICS_PORT.to_string()
+        },{
+            "name": "NODE_IP",
+            "valueFrom": {
+                "fieldRef": {
+                    "apiVersion": "v1",
+                    "fieldPath": "status.podIP",
+                }
+            }
+        }],
+        "ports": [{
+            "containerPort": METRICS_PORT,
+            "name": "metrics",
+            "protocol": "TCP",
+        }],
+        "resources": {
+            "requests": {
+                "cpu": "10m",
+                "memory": "32Mi"
+            }
+        }
+    })
+}
+
+/// Headless Service and ServiceMonitor scraping the `metrics` port of the
+/// pods matching `labels`. Every series carries the Noria name and, for
+/// components of a deployment, its id.
+pub fn children(
+    monitoring: &MonitoringConfig,
+    name: &str,
+    namespace: &str,
+    labels: Value,
+    noria_name: &str,
+    deployment_id: Option<&str>,
+    port: u16,
+) -> Vec<Value> {
+    let service_name = format!("{}-metrics", name);
+
+    let mut service_labels = labels.clone();
+    service_labels["noria-operator.io/metrics"] = json!("true");
+
+    let mut relabelings = vec![json!({
+        "targetLabel": "noria",
+        "replacement": noria_name,
+    })];
+    if let Some(id) = deployment_id {
+        relabelings.push(json!({
+            "targetLabel": "deployment",
+            "replacement": id,
+        }));
+    }
+
+    let mut service_monitor = json!({
+        "apiVersion": "monitoring.coreos.com/v1",
+        "kind": "ServiceMonitor",
+        "metadata": {
+            "name": name,
+            "namespace": namespace,
+        },
+        "spec": {
+            "selector": {
+                "matchLabels": service_labels
+            },
+            "endpoints": [{
+                "port": "metrics",
+                "path": "/metrics",
+                "interval": monitoring
+                    .interval
+                    .as_deref()
+                    .unwrap_or(DEFAULT_SCRAPE_INTERVAL),
+                "relabelings": relabelings,
+            }]
+        }
+    });
+
+    // Prometheus usually selects ServiceMonitors by label
+    if let Some(monitor_labels) = &monitoring.labels {
+        service_monitor["metadata"]["labels"] = json!(monitor_labels);
+    }
+
+    vec![
+        json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": {
+                "name": service_name,
+                "namespace": namespace,
+                "labels": service_labels,
+            },
+            "spec": {
+                "clusterIP": "None",
+                "ports": [{
+                    "port": port,
+                    "name": "metrics",
+                    "targetPort": port,
+                }],
+                "selector": labels
+            }
+        }),
+        service_monitor,
+    ]
+}
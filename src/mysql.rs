@@ -1,28 +1,131 @@
+use super::disruption;
+use super::monitoring;
+use super::resources::{self, DEFAULT_MEMORY_MULTIPLIER};
+use super::scheduling;
+use super::tls;
 use super::Builder;
-use super::NoriaMysqlConfig;
-use super::{DEFAULT_NORIA_VERSION, NORIA_IMAGE, ZOOKEEPER_CLIENT_SERVICE_NAME};
+use super::{MonitoringConfig, NoriaMysqlConfig, SchedulingConfig, TlsConfig};
+use super::{MysqlAutoscalingConfig, MysqlCredentialsConfig, MysqlServiceConfig};
+use super::{DEFAULT_NORIA_VERSION, NORIA_IMAGE, PROXYSQL_IMAGE};
 
 use roperator::serde_json::{json, Value};
 
 pub struct Config<'svc> {
     id: &'svc str,
     name: String,
+    noria_name: &'svc str,
+    instance: String,
+    zookeeper_address: &'svc str,
     version: &'svc str,
-    replicas: usize,
+    replicas: Option<usize>,
+    resources: Value,
+    scheduling: Option<&'svc SchedulingConfig>,
+    disruption_budget: Option<Value>,
+    autoscaling: Option<&'svc MysqlAutoscalingConfig>,
+    credentials: Option<&'svc MysqlCredentialsConfig>,
+    tls: Option<&'svc TlsConfig>,
+    service: Option<&'svc MysqlServiceConfig>,
+    monitoring: Option<&'svc MonitoringConfig>,
+    secret_checksums: Value,
 }
 
 // Default Noria Mysql settings
 const DEFAULT_NORIA_MYSQL_REPLICAS: usize = 3;
 
+// CPU requests, which the autoscaler measures utilization against
+const NORIA_MYSQL_CPU_REQUEST: &str = "100m";
+const PROXYSQL_CPU_REQUEST: &str = "50m";
+
+const DEFAULT_MIN_REPLICAS: usize = 1;
+const DEFAULT_TARGET_CPU_UTILIZATION: u32 = 80;
+
+/// Where the username and password files are mounted
+pub const CREDENTIALS_PATH: &str = "/etc/noria-mysql/credentials";
+
+const DEFAULT_USERNAME_KEY: &str = "username";
+const DEFAULT_PASSWORD_KEY: &str = "password";
+
+// With TLS, ProxySQL takes over the clients port and forwards to noria-mysql,
+// which then only listens on the loopback interface
+const TLS_SECRET_PATH: &str = "/etc/noria-mysql/tls";
+const PROXYSQL_DATA_PATH: &str = "/var/lib/proxysql";
+const LOOPBACK_PORT: u16 = 3307;
+// ProxySQL serves Prometheus metrics from its REST API
+const PROXYSQL_METRICS_PORT: u16 = 6070;
+
+/// Volume with the credentials Secret, its keys mapped to `username` and `password` files
+pub fn credentials_volume(credentials: &MysqlCredentialsConfig) -> Value {
+    json!({
+        "name": "credentials",
+        "secret": {
+            "secretName": credentials.secret,
+            "items": [{
+                "key": credentials.username_key.as_deref().unwrap_or(DEFAULT_USERNAME_KEY),
+                "path": "username",
+            }, {
+                "key": credentials.password_key.as_deref().unwrap_or(DEFAULT_PASSWORD_KEY),
+                "path": "password",
+            }]
+        }
+    })
+}
+
+/// Name of the noria-mysql Deployment and Service of a deployment
+pub fn name(noria_name: &str, deployment_id: &str) -> String {
+    format!("noria-mysql-{}-{}", noria_name, deployment_id)
+}
+
+/// Certificate Secret of a deployment with TLS enabled
+pub fn tls_secret(
+    noria_name: &str,
+    noria_mysql: &Option<NoriaMysqlConfig>,
+    deployment_id: &str,
+) -> Option<String> {
+    match noria_mysql {
+        Some(NoriaMysqlConfig { tls: Some(tls), .. }) => {
+            Some(tls::secret_name(tls, &name(noria_name, deployment_id)))
+        }
+        _ => None,
+    }
+}
+
+/// Whether the deployment runs noria-mysql, unless `enabled: false`
+pub fn enabled(noria_mysql: &Option<NoriaMysqlConfig>) -> bool {
+    match noria_mysql {
+        Some(NoriaMysqlConfig {
+            enabled: Some(enabled),
+            ..
+        }) => *enabled,
+        _ => true,
+    }
+}
+
 pub fn create_config<'svc>(
+    noria_name: &'svc str,
     noria_mysql: &'svc Option<NoriaMysqlConfig>,
     deployment_id: &'svc str,
+    zookeeper_address: &'svc str,
+    secret_checksums: Value,
+    monitoring: Option<&'svc MonitoringConfig>,
 ) -> Config<'svc> {
-    let noria_mysql_replicas = match noria_mysql {
+    let noria_mysql_autoscaling = match noria_mysql {
         Some(NoriaMysqlConfig {
-            replicas: Some(n), ..
-        }) => *n,
-        _ => DEFAULT_NORIA_MYSQL_REPLICAS,
+            autoscaling: Some(a),
+            ..
+        }) => Some(a),
+        _ => None,
+    };
+
+    // With autoscaling the replicas are left to the autoscaler
+    let noria_mysql_replicas = match (noria_mysql, noria_mysql_autoscaling) {
+        (_, Some(_)) => None,
+        (
+            Some(NoriaMysqlConfig {
+                replicas: Some(n), ..
+            }),
+            None,
+        ) => Some(*n),
+        _ => Some(DEFAULT_NORIA_MYSQL_REPLICAS),
     };
 
     let noria_mysql_version = match noria_mysql {
@@ -32,13 +135,83 @@ pub fn create_config<'svc>(
         _ => DEFAULT_NORIA_VERSION,
     };
 
-    let noria_mysql_name = format!("noria-mysql-{}", deployment_id);
+    let noria_mysql_credentials = match noria_mysql {
+        Some(NoriaMysqlConfig {
+            credentials: Some(c),
+            ..
+        }) => Some(c),
+        _ => None,
+    };
+
+    let noria_mysql_tls = match noria_mysql {
+        Some(NoriaMysqlConfig { tls: Some(t), .. }) => Some(t),
+        _ => None,
+    };
+
+    let noria_mysql_service = match noria_mysql {
+        Some(NoriaMysqlConfig {
+            service: Some(s), ..
+        }) => Some(s),
+        _ => None,
+    };
+
+    let noria_mysql_memory_multiplier = match noria_mysql {
+        Some(NoriaMysqlConfig {
+            memory_multiplier: Some(m),
+            ..
+        }) => *m,
+        _ => DEFAULT_MEMORY_MULTIPLIER,
+    };
+
+    let noria_mysql_resources = match noria_mysql {
+        Some(NoriaMysqlConfig {
+            max_heap,
+            resources: r,
+            ..
+        }) => resources::container_resources(
+            *max_heap,
+            noria_mysql_memory_multiplier,
+            r,
+            json!({ "requests": { "cpu": NORIA_MYSQL_CPU_REQUEST } }),
+        ),
+        None => json!({ "requests": { "cpu": NORIA_MYSQL_CPU_REQUEST } }),
+    };
+
+    let noria_mysql_scheduling = match noria_mysql {
+        Some(NoriaMysqlConfig {
+            scheduling: Some(s),
+            ..
+        }) => Some(s),
+        _ => None,
+    };
+
+    // Only with a budget in the spec, noria-mysql holds no state
+    let noria_mysql_disruption_budget = match noria_mysql {
+        Some(NoriaMysqlConfig {
+            disruption_budget, ..
+        }) => disruption::budget(disruption_budget.as_ref(), false),
+        None => None,
+    };
+
+    let noria_mysql_name = name(noria_name, deployment_id);
 
     Config {
         name: noria_mysql_name,
+        noria_name,
+        instance: format!("{}-{}", noria_name, deployment_id),
+        zookeeper_address,
         id: deployment_id,
         version: noria_mysql_version,
         replicas: noria_mysql_replicas,
+        resources: noria_mysql_resources,
+        scheduling: noria_mysql_scheduling,
+        disruption_budget: noria_mysql_disruption_budget,
+        autoscaling: noria_mysql_autoscaling,
+        credentials: noria_mysql_credentials,
+        tls: noria_mysql_tls,
+        service: noria_mysql_service,
+        monitoring,
+        secret_checksums,
     }
 }
 
@@ -46,12 +219,17 @@ impl<'svc> Builder for Config<'svc> {
     fn children(self: &Config<'svc>, namespace: &str) -> Vec<Value> {
         let mut children = vec![];
         // Noria-mysql Service
-        children.push(json!({
+        let mut service = json!({
             "apiVersion": "v1",
             "kind": "Service",
             "metadata": {
                 "name": self.name,
                 "namespace": namespace,
+                "labels": {
+                    "noria-operator.io/kind": "noria-mysql",
+                    "noria-operator.io/name": self.instance,
+                    "noria-operator.io/deployment": self.id
+                }
             },
             "spec": {
                 "ports": [{
@@ -61,31 +239,86 @@ impl<'svc> Builder for Config<'svc> {
                 }],
                 "selector": {
                     "noria-operator.io/kind": "noria-mysql",
-                    "noria-operator.io/name": self.id
+                    "noria-operator.io/name": self.instance
                 }
             }
-        }));
+        });
+
+        // Exposes noria-mysql outside the cluster, the resolved endpoint is
+        // reported in the status
+        if let Some(config) = self.service {
+            if let Some(service_type) = &config.service_type {
+                service["spec"]["type"] = json!(service_type);
+            }
+            if let Some(annotations) = &config.annotations {
+                service["metadata"]["annotations"] = json!(annotations);
+            }
+            if let Some(ranges) = &config.load_balancer_source_ranges {
+                service["spec"]["loadBalancerSourceRanges"] = json!(ranges);
+            }
+            if let Some(node_port) = config.node_port {
+                service["spec"]["ports"][0]["nodePort"] = json!(node_port);
+            }
+            if let Some(policy) = &config.external_traffic_policy {
+                service["spec"]["externalTrafficPolicy"] = json!(policy);
+            }
+        }
+
+        children.push(service);
+
+        // With credentials, noria-mysql only listens on the loopback interface
+        // and ProxySQL authenticates clients in front of it
+        let (listen_address, ping_address) = match self.credentials {
+            Some(_) => (
+                format!("127.0.0.1:{}", LOOPBACK_PORT),
+                format!("-h 127.0.0.1 -P {}", LOOPBACK_PORT),
+            ),
+            None => (
+                String::from("${NODE_IP}:3306"),
+                String::from("-h \"$NODE_IP\""),
+            ),
+        };
+
+        if let Some(tls) = self.tls {
+            if let Some(certificate) = tls::certificate(
+                tls,
+                &self.name,
+                namespace,
+                vec![
+                    self.name.clone(),
+                    format!("{}.{}.svc", self.name, namespace),
+                ],
+            ) {
+                children.push(certificate);
+            }
+        }
 
         let noria_mysql_command = format!(
-            r#"/usr/local/bin/noria-mysql --address ${{NODE_IP}}:3306 \
-              --deployment {} --zookeeper-address {}:2181"#,
-            self.id, ZOOKEEPER_CLIENT_SERVICE_NAME
+            r#"/usr/local/bin/noria-mysql --address {} \
+              --deployment {} --zookeeper-address {}"#,
+            listen_address, self.id, self.zookeeper_address
         );
+        let ping_command = format!("mysqladmin ping {}", ping_address);
 
         // Noria-mysql Deployment
-        children.push(json!({
-            "apiVersion": "extensions/v1beta1",
+        let mut deployment = json!({
+            "apiVersion": "apps/v1",
             "kind": "Deployment",
             "metadata": {
                 "name": self.name,
                 "namespace": namespace,
+                "labels": {
+                    "noria-operator.io/kind": "noria-mysql",
+                    "noria-operator.io/name": self.instance,
+                    "noria-operator.io/deployment": self.id
+                }
             },
             "spec": {
                 "replicas": self.replicas,
                 "selector": {
                     "matchLabels": {
                         "noria-operator.io/kind": "noria-mysql",
-                        "noria-operator.io/name": self.id,
+                        "noria-operator.io/name": self.instance,
                     }
                 },
                 "strategy": {
@@ -96,7 +329,7 @@ impl<'svc> Builder for Config<'svc> {
                         "name": self.name,
                         "labels": {
                             "noria-operator.io/kind": "noria-mysql",
-                            "noria-operator.io/name": self.id
+                            "noria-operator.io/name": self.instance
                         }
                     },
                     "spec": {
@@ -118,6 +351,7 @@ impl<'svc> Builder for Config<'svc> {
                             "image": format!("{}:{}", NORIA_IMAGE, self.version),
                             "imagePullPolicy": "Always",
                             "name": "noria-mysql",
+                            "resources": self.resources,
                             "ports": [{
                                 "containerPort": 3306,
                                 "name": "clients",
@@ -125,11 +359,7 @@ impl<'svc> Builder for Config<'svc> {
                             }],
                             "livenessProbe": {
                                 "exec": {
-                                    "command": [
-                                        "bash",
-                                        "-exc",
-                                        "mysqladmin ping -h \"$NODE_IP\"",
-                                    ]
+                                    "command": ["bash", "-exc", ping_command]
                                 },
                                 "failureThreshold": 3,
                                 "initialDelaySeconds": 60,
@@ -141,7 +371,241 @@ impl<'svc> Builder for Config<'svc> {
                     }
                 }
             }
-        }));
+        });
+
+        // A changed checksum rolls the pods, so that they pick up rotated
+        // credentials and certificates
+        if let Some(credentials) = self.credentials {
+            let pod = &mut deployment["spec"]["template"];
+            pod["metadata"]["annotations"] = self.secret_checksums.clone();
+            pod["spec"]["volumes"] = json!([credentials_volume(credentials)]);
+        }
+
+        // ProxySQL authenticates clients, so that the credentials only end up
+        // in its config file rather than on a command line, and negotiates
+        // TLS within the MySQL protocol. Its admin interface is only used from
+        // inside the pod, so it gets a random password, and the credentials
+        // are escaped for the config's quoted strings.
+        if self.credentials.is_some() {
+            let copy_certificates = match self.tls {
+                Some(_) => format!(
+                    r#"cp {tls}/tls.crt {data}/proxysql-cert.pem
+              cp {tls}/tls.key {data}/proxysql-key.pem
+              cp {tls}/ca.crt {data}/proxysql-ca.pem
+              "#,
+                    tls = TLS_SECRET_PATH,
+                    data = PROXYSQL_DATA_PATH,
+                ),
+                None => String::new(),
+            };
+            let (have_ssl, use_ssl) = match self.tls {
+                Some(_) => ("true", 1),
+                None => ("false", 0),
+            };
+            let proxysql_config = format!(
+                r#"{copy_certificates}              escape() {{ sed -e 's/\\/\\\\/g' -e 's/"/\\"/g' "$1"; }}
+              admin_password=$(head -c 32 /dev/urandom | base64 | tr -dc 'A-Za-z0-9')
+              {{
+                echo 'datadir="{data}"'
+                echo "admin_variables={{ admin_credentials=\"admin:$admin_password\" mysql_ifaces=\"127.0.0.1:6032\" {restapi} }}"
+                echo 'mysql_variables={{ interfaces="0.0.0.0:3306" have_ssl={have_ssl} monitor_enabled=false }}'
+                echo 'mysql_servers=({{ address="127.0.0.1" port={port} hostgroup=0 }})'
+                echo "mysql_users=({{ username=\"$(escape {credentials}/username)\" password=\"$(escape {credentials}/password)\" default_hostgroup=0 use_ssl={use_ssl} }})"
+              }} > {data}/proxysql.cnf"#,
+                copy_certificates = copy_certificates,
+                have_ssl = have_ssl,
+                use_ssl = use_ssl,
+                data = PROXYSQL_DATA_PATH,
+                port = LOOPBACK_PORT,
+                credentials = CREDENTIALS_PATH,
+                restapi = match self.monitoring {
+                    Some(_) => format!(
+                        "restapi_enabled=true restapi_port={}",
+                        PROXYSQL_METRICS_PORT
+                    ),
+                    None => String::new(),
+                }
+            );
+
+            let pod = &mut deployment["spec"]["template"]["spec"];
+            pod["containers"][0]["ports"] = json!([]);
+            pod["initContainers"] = json!([{
+                "name": "init-proxysql",
+                "image": PROXYSQL_IMAGE,
+                "command": ["bash", "-ec"],
+                "args": [proxysql_config],
+                "volumeMounts": [{
+                    "mountPath": CREDENTIALS_PATH,
+                    "name": "credentials",
+                    "readOnly": true
+                }, {
+                    "mountPath": PROXYSQL_DATA_PATH,
+                    "name": "proxysql"
+                }]
+            }]);
+            if let Some(containers) = pod["containers"].as_array_mut() {
+                containers.push(json!({
+                    "name": "proxysql",
+                    "image": PROXYSQL_IMAGE,
+                    "resources": {
+                        "requests": {
+                            "cpu": PROXYSQL_CPU_REQUEST
+                        }
+                    },
+                    "command": ["proxysql", "-f", "--initial"],
+                    "args": [
+                        "-c", format!("{}/proxysql.cnf", PROXYSQL_DATA_PATH),
+                        "-D", PROXYSQL_DATA_PATH
+                    ],
+                    "ports": [{
+                        "containerPort": 3306,
+                        "name": "clients",
+                        "protocol": "TCP",
+                    }],
+                    "livenessProbe": {
+                        "tcpSocket": {
+                            "port": 3306
+                        },
+                        "failureThreshold": 3,
+                        "initialDelaySeconds": 10,
+                        "periodSeconds": 10,
+                        "successThreshold": 1,
+                        "timeoutSeconds": 5,
+                    },
+                    "volumeMounts": [{
+                        "mountPath": PROXYSQL_DATA_PATH,
+                        "name": "proxysql"
+                    }]
+                }));
+            }
+            if self.monitoring.is_some() {
+                if let Some(ports) = pod["containers"][1]["ports"].as_array_mut() {
+                    ports.push(json!({
+                        "containerPort": PROXYSQL_METRICS_PORT,
+                        "name": "metrics",
+                        "protocol": "TCP",
+                    }));
+                }
+            }
+            if let Some(volumes) = pod["volumes"].as_array_mut() {
+                volumes.push(json!({
+                    "name": "proxysql",
+                    "emptyDir": {
+                        "medium": "Memory"
+                    }
+                }));
+            }
+            if let Some(tls) = self.tls {
+                if let Some(mounts) = pod["initContainers"][0]["volumeMounts"].as_array_mut() {
+                    mounts.push(json!({
+                        "mountPath": TLS_SECRET_PATH,
+                        "name": "tls",
+                        "readOnly": true
+                    }));
+                }
+                if let Some(volumes) = pod["volumes"].as_array_mut() {
+                    volumes.push(tls::volume(&tls::secret_name(tls, &self.name)));
+                }
+            }
+        }
+
+        scheduling::apply(
+            &mut deployment["spec"]["template"]["spec"],
+            self.scheduling,
+            None,
+        );
+
+        if self.replicas.is_none() {
+            if let Some(spec) = deployment["spec"].as_object_mut() {
+                spec.remove("replicas");
+            }
+        }
+
+        children.push(deployment);
+
+        // noria-mysql has no metrics of its own, those of the ProxySQL in
+        // front of it are scraped, so without credentials it is not monitored
+        if let (Some(monitoring), Some(_)) = (self.monitoring, self.credentials) {
+            children.append(&mut monitoring::children(
+                monitoring,
+                &self.name,
+                namespace,
+                json!({
+                    "noria-operator.io/kind": "noria-mysql",
+                    "noria-operator.io/name": self.instance,
+                }),
+                self.noria_name,
+                Some(self.id),
+                PROXYSQL_METRICS_PORT,
+            ));
+        }
+
+        // Noria-mysql PodDisruptionBudget
+        if let Some(budget) = &self.disruption_budget {
+            children.push(disruption::pod_disruption_budget(
+                &self.name,
+                namespace,
+                json!({
+                    "noria-operator.io/kind": "noria-mysql",
+                    "noria-operator.io/name": self.instance,
+                }),
+                budget.clone(),
+            ));
+        }
+
+        // Noria-mysql HorizontalPodAutoscaler
+        if let Some(autoscaling) = self.autoscaling {
+            let mut metrics = vec![json!({
+                "type": "Resource",
+                "resource": {
+                    "name": "cpu",
+                    "target": {
+                        "type": "Utilization",
+                        "averageUtilization": autoscaling
+                            .target_cpu_utilization
+                            .unwrap_or(DEFAULT_TARGET_CPU_UTILIZATION),
+                    }
+                }
+            })];
+            if let Some(metric) = &autoscaling.metric {
+                metrics.push(json!({
+                    "type": "Pods",
+                    "pods": {
+                        "metric": {
+                            "name": metric.name
+                        },
+                        "target": {
+                            "type": "AverageValue",
+                            "averageValue": metric.target_average_value,
+                        }
+                    }
+                }));
+            }
+
+            children.push(json!({
+                "apiVersion": "autoscaling/v2",
+                "kind": "HorizontalPodAutoscaler",
+                "metadata": {
+                    "name": self.name,
+                    "namespace": namespace,
+                    "labels": {
+                        "noria-operator.io/kind": "noria-mysql",
+                        "noria-operator.io/name": self.instance,
+                        "noria-operator.io/deployment": self.id
+                    }
+                },
+                "spec": {
+                    "scaleTargetRef": {
+                        "apiVersion": "apps/v1",
+                        "kind": "Deployment",
+                        "name": self.name,
+                    },
+                    "minReplicas": autoscaling.min_replicas.unwrap_or(DEFAULT_MIN_REPLICAS),
+                    "maxReplicas": autoscaling.max_replicas,
+                    "metrics": metrics,
+                }
+            }));
+        }
 
         children
     }
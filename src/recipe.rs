@@ -0,0 +1,196 @@
+use super::api::{self, Api};
+use super::mysql;
+use super::Builder;
+use super::MYSQL_CLIENT_IMAGE;
+use super::{MysqlCredentialsConfig, RecipeConfig};
+
+use roperator::prelude::*;
+use roperator::serde_json::{json, Value};
+
+/// Annotation on the recipe Job with the hash of the recipe it applies
+pub const RECIPE_HASH_ANNOTATION: &str = "noria-operator.io/recipe-hash";
+
+const DEFAULT_RECIPE_KEY: &str = "recipe.sql";
+
+// Attempts of a recipe Job, which first waits for noria-mysql to come up
+const BACKOFF_LIMIT: u32 = 6;
+
+pub struct Config<'svc> {
+    name: String,
+    id: &'svc str,
+    instance: String,
+    mysql_name: String,
+    config_map: &'svc str,
+    key: &'svc str,
+    hash: &'svc str,
+    credentials: Option<&'svc MysqlCredentialsConfig>,
+    tls: bool,
+}
+
+fn key(recipe: &RecipeConfig) -> &str {
+    match recipe {
+        RecipeConfig { key: Some(k), .. } => k,
+        _ => DEFAULT_RECIPE_KEY,
+    }
+}
+
+/// Name of a deployment's recipe Jobs, followed by the recipe hash
+pub fn name(noria_name: &str, deployment_id: &str) -> String {
+    format!("noria-recipe-{}-{}", noria_name, deployment_id)
+}
+
+/// Whether the recipe Job observed for a child ran out of retries. It is left
+/// out of the children, so that it is deleted and created again on the next
+/// sync.
+pub fn failed(request: &SyncRequest, child: &Value) -> bool {
+    if child["kind"] != "Job"
+        || child["metadata"]["labels"]["noria-operator.io/kind"] != "noria-recipe"
+    {
+        return false;
+    }
+
+    let existing = match (
+        child["metadata"]["namespace"].as_str(),
+        child["metadata"]["name"].as_str(),
+    ) {
+        (Some(namespace), Some(name)) => request
+            .children()
+            .of_type(("batch/v1", "Job"))
+            .get(namespace, name),
+        _ => None,
+    };
+
+    existing
+        .and_then(|job| job.pointer("/status/conditions"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .any(|c| c["type"] == "Failed" && c["status"] == "True")
+}
+
+/// Hash of the recipe in the referenced ConfigMap, None while the ConfigMap or
+/// its key does not exist
+pub fn hash(api: &Api, namespace: &str, recipe: &RecipeConfig) -> Result<Option<String>, Error> {
+    let config_map = api.config_map(namespace, &recipe.config_map)?;
+
+    Ok(config_map
+        .as_ref()
+        .and_then(|config_map| config_map["data"][key(recipe)].as_str())
+        .map(|content| api::digest(content.as_bytes())))
+}
+
+pub fn create_config<'svc>(
+    noria_name: &'svc str,
+    recipe: &'svc RecipeConfig,
+    hash: &'svc str,
+    credentials: Option<&'svc MysqlCredentialsConfig>,
+    tls: bool,
+    deployment_id: &'svc str,
+) -> Config<'svc> {
+    Config {
+        // a changed recipe is a new Job, the previous one is deleted
+        name: format!("{}-{}", name(noria_name, deployment_id), hash),
+        id: deployment_id,
+        instance: format!("{}-{}", noria_name, deployment_id),
+        mysql_name: mysql::name(noria_name, deployment_id),
+        config_map: &recipe.config_map,
+        key: key(recipe),
+        hash,
+        credentials,
+        tls,
+    }
+}
+
+impl<'svc> Builder for Config<'svc> {
+    fn children(self: &Config<'svc>, namespace: &str) -> Vec<Value> {
+        let ssl_mode = if self.tls { "REQUIRED" } else { "PREFERRED" };
+
+        // noria-mysql only comes up after ZooKeeper and noria-server
+        let wait_command = format!(
+            r#"until (exec 3<>/dev/tcp/{host}/3306) 2> /dev/null; do
+                  echo "Waiting for {host}"
+                  sleep 5
+                done
+                "#,
+            host = self.mysql_name
+        );
+
+        let apply_command = match self.credentials {
+            Some(_) => format!(
+                r#"MYSQL_PWD="$(cat {path}/password)" \
+                  mysql -h {} -P 3306 --ssl-mode={} -u "$(cat {path}/username)" < /recipe/{}"#,
+                self.mysql_name,
+                ssl_mode,
+                self.key,
+                path = mysql::CREDENTIALS_PATH
+            ),
+            None => format!(
+                r#"mysql -h {} -P 3306 --ssl-mode={} < /recipe/{}"#,
+                self.mysql_name, ssl_mode, self.key
+            ),
+        };
+
+        // Recipe applier Job, kept after it completes so that the recipe is applied once
+        let mut job = json!({
+            "apiVersion": "batch/v1",
+            "kind": "Job",
+            "metadata": {
+                "name": self.name,
+                "namespace": namespace,
+                "labels": {
+                    "noria-operator.io/kind": "noria-recipe",
+                    "noria-operator.io/name": self.instance,
+                    "noria-operator.io/deployment": self.id
+                }
+            },
+            "spec": {
+                "backoffLimit": BACKOFF_LIMIT,
+                "template": {
+                    "metadata": {
+                        "labels": {
+                            "noria-operator.io/kind": "noria-recipe",
+                            "noria-operator.io/name": self.instance
+                        }
+                    },
+                    "spec": {
+                        "restartPolicy": "Never",
+                        "containers": [{
+                            "name": "apply",
+                            "image": MYSQL_CLIENT_IMAGE,
+                            "command": ["bash", "-ec"],
+                            "args": [format!("{}{}", wait_command, apply_command)],
+                            "volumeMounts": [{
+                                "mountPath": "/recipe",
+                                "name": "recipe"
+                            }]
+                        }],
+                        "volumes": [{
+                            "name": "recipe",
+                            "configMap": {
+                                "name": self.config_map
+                            }
+                        }]
+                    }
+                }
+            }
+        });
+
+        job["metadata"]["annotations"][RECIPE_HASH_ANNOTATION] = json!(self.hash);
+
+        if let Some(credentials) = self.credentials {
+            let pod = &mut job["spec"]["template"]["spec"];
+            if let Some(volumes) = pod["volumes"].as_array_mut() {
+                volumes.push(mysql::credentials_volume(credentials));
+            }
+            if let Some(mounts) = pod["containers"][0]["volumeMounts"].as_array_mut() {
+                mounts.push(json!({
+                    "mountPath": mysql::CREDENTIALS_PATH,
+                    "name": "credentials",
+                    "readOnly": true
+                }));
+            }
+        }
+
+        vec![job]
+    }
+}
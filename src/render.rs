@@ -0,0 +1,34 @@
+use super::model::Noria;
+use super::{create_children, References};
+
+use roperator::prelude::*;
+use roperator::serde_json::{self, Value};
+
+use std::fs;
+use std::path::Path;
+
+pub fn render(file: &Path, namespace: &str) -> Result<String, Error> {
+    let mut manifest: Value = serde_yaml::from_str(&fs::read_to_string(file)?)?;
+
+    // kubectl applies manifests without a namespace to the current one, do the same
+    if let Some(metadata) = manifest
+        .get_mut("metadata")
+        .and_then(|metadata| metadata.as_object_mut())
+    {
+        metadata
+            .entry("namespace")
+            .or_insert_with(|| Value::String(namespace.to_string()));
+    }
+
+    let crd: Noria = serde_json::from_value(manifest)?;
+
+    let mut rendered = String::new();
+    // referenced ConfigMaps and Secrets are not read from the cluster, so recipe
+    // Jobs are left out and Secret checksums are empty
+    for child in create_children(&crd, None, &References::default())? {
+        rendered.push_str(&serde_yaml::to_string(&child)?);
+        rendered.push('\n');
+    }
+
+    Ok(rendered)
+}
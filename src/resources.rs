@@ -0,0 +1,185 @@
+use super::ResourcesConfig;
+
+use roperator::serde_json::{json, Value};
+
+/// Container memory per MiB of heap, unless a component sets `memory_multiplier`
+pub const DEFAULT_MEMORY_MULTIPLIER: f64 = 1.3;
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Bytes in a Kubernetes quantity, such as `512Mi`, `1G`, `1e9` or `1048576`,
+/// rounded up like Kubernetes does. None for anything outside the quantity
+/// grammar and for negative quantities.
+pub fn parse_quantity(quantity: &str) -> Option<u64> {
+    let quantity = quantity.trim();
+    let split = quantity
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (i == 0 && (c == '+' || c == '-'))))
+        .map_or(quantity.len(), |(i, _)| i);
+    let (number, suffix) = quantity.split_at(split);
+
+    let (binary, decimal): (u32, i32) = match suffix {
+        "" => (0, 0),
+        "m" => (0, -3),
+        "k" => (0, 3),
+        "M" => (0, 6),
+        "G" => (0, 9),
+        "T" => (0, 12),
+        "P" => (0, 15),
+        "E" => (0, 18),
+        "Ki" => (10, 0),
+        "Mi" => (20, 0),
+        "Gi" => (30, 0),
+        "Ti" => (40, 0),
+        "Pi" => (50, 0),
+        "Ei" => (60, 0),
+        _ if suffix.starts_with('e') || suffix.starts_with('E') => (0, suffix[1..].parse().ok()?),
+        _ => return None,
+    };
+
+    let (sign, number) = match number.as_bytes().first() {
+        Some(b'-') => (-1, &number[1..]),
+        Some(b'+') => (1, &number[1..]),
+        _ => (1, number),
+    };
+
+    // the digits as an integer, scaled down by the fraction's length
+    let (whole, fraction) = match number.find('.') {
+        Some(dot) => (&number[..dot], &number[dot + 1..]),
+        None => (number, ""),
+    };
+    if whole.is_empty() && fraction.is_empty() || fraction.contains('.') {
+        return None;
+    }
+    let digits = format!("{}{}", whole, fraction).parse::<u128>().ok()?;
+    if digits > 0 && sign < 0 {
+        return None;
+    }
+
+    let scaled = digits.checked_mul(1u128.checked_shl(binary)?)?;
+    let exponent = decimal - fraction.len() as i32;
+    let bytes = if exponent >= 0 {
+        scaled.checked_mul(10u128.checked_pow(exponent as u32)?)?
+    } else {
+        let divisor = 10u128.checked_pow(-exponent as u32)?;
+        scaled.checked_add(divisor - 1)? / divisor
+    };
+
+    if bytes > u128::from(u64::MAX) {
+        return None;
+    }
+    Some(bytes as u64)
+}
+
+fn memory_limit(resources: &Option<ResourcesConfig>) -> Option<u64> {
+    resources
+        .as_ref()
+        .and_then(|resources| resources.limits.as_ref())
+        .and_then(|limits| limits.get("memory"))
+        .and_then(|memory| match memory {
+            Value::String(quantity) => parse_quantity(quantity),
+            Value::Number(bytes) => bytes.as_u64(),
+            _ => None,
+        })
+}
+
+/// Heap in MiB handed to the process. An explicit memory limit takes
+/// precedence over `max_heap`, so that the two stay in proportion.
+pub fn heap_size(max_heap: u64, multiplier: f64, resources: &Option<ResourcesConfig>) -> u64 {
+    match memory_limit(resources) {
+        Some(bytes) => (bytes as f64 / multiplier / MIB) as u64,
+        None => max_heap,
+    }
+}
+
+/// Container resources: the memory derived from the heap and the component's
+/// defaults, overridden entry by entry by the spec
+pub fn container_resources(
+    heap: Option<u64>,
+    multiplier: f64,
+    resources: &Option<ResourcesConfig>,
+    defaults: Value,
+) -> Value {
+    let mut container = json!({});
+
+    for kind in &["requests", "limits"] {
+        if let Some(list) = defaults.get(kind).and_then(Value::as_object) {
+            for (name, quantity) in list {
+                container[kind][name] = quantity.clone();
+            }
+        }
+    }
+
+    if let Some(heap) = heap {
+        let memory = format!("{}Mi", (multiplier * heap as f64) as u64);
+        container["requests"]["memory"] = json!(memory);
+        container["limits"]["memory"] = json!(memory);
+    }
+
+    if let Some(resources) = resources {
+        for (name, quantity) in resources.requests.iter().flatten() {
+            container["requests"][name] = quantity.clone();
+        }
+        for (name, quantity) in resources.limits.iter().flatten() {
+            container["limits"][name] = quantity.clone();
+        }
+    }
+
+    container
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_quantity_cases() {
+        let cases = [
+            ("1048576", Some(1 << 20)),
+            ("512Mi", Some(512 << 20)),
+            ("1.5Gi", Some(3 << 29)),
+            ("1G", Some(1_000_000_000)),
+            ("1e9", Some(1_000_000_000)),
+            ("1E9", Some(1_000_000_000)),
+            ("5e-1", Some(1)),
+            ("1.5e3", Some(1500)),
+            ("2P", Some(2_000_000_000_000_000)),
+            ("2Pi", Some(2 << 50)),
+            ("1E", Some(1_000_000_000_000_000_000)),
+            ("1Ei", Some(1 << 60)),
+            ("1500m", Some(2)),
+            ("0.5", Some(1)),
+            (".5Ki", Some(512)),
+            ("+1k", Some(1000)),
+            ("-0", Some(0)),
+            (" 64Mi ", Some(64 << 20)),
+            ("-1Mi", None),
+            ("16Ei", None),
+            ("1MB", None),
+            ("1mi", None),
+            ("1e", None),
+            ("1.2.3", None),
+            ("Mi", None),
+            ("", None),
+        ];
+
+        for (quantity, bytes) in &cases {
+            assert_eq!(parse_quantity(quantity), *bytes, "{:?}", quantity);
+        }
+    }
+
+    #[test]
+    fn heap_size_follows_memory_limit() {
+        let resources = Some(ResourcesConfig {
+            requests: None,
+            limits: Some(
+                vec![("memory".to_string(), json!("1.3Gi"))]
+                    .into_iter()
+                    .collect(),
+            ),
+        });
+
+        assert_eq!(heap_size(512, 1.3, &resources), 1024);
+        assert_eq!(heap_size(512, 1.3, &None), 512);
+    }
+}
@@ -0,0 +1,229 @@
+use super::backup;
+use super::server;
+use super::Builder;
+use super::{RestoreConfig, S3Config};
+use super::{AWS_CLI_IMAGE, KUBECTL_IMAGE, NORIA_IMAGE};
+
+use k8s_types::apps::v1 as apps;
+
+use roperator::prelude::*;
+use roperator::serde_json::{json, Value};
+
+const DATA_PATH: &str = "/var/lib/noria";
+const RESTORE_PATH: &str = "/restore";
+
+pub struct Config<'svc> {
+    name: String,
+    backup: &'svc str,
+    claims: Vec<Value>,
+    from_snapshots: bool,
+}
+
+fn job_name(statefulset_name: &str) -> String {
+    format!("{}-restore", statefulset_name)
+}
+
+/// Whether the noria-server StatefulSet waits for its volumes to be restored
+/// from VolumeSnapshots. A StatefulSet that already exists has its volumes.
+pub fn pending(
+    request: &SyncRequest,
+    restore: &RestoreConfig,
+    noria_name: &str,
+    namespace: &str,
+    deployment_id: &str,
+) -> bool {
+    if restore.s3.is_some() {
+        return false;
+    }
+
+    let statefulset_name = server::name(noria_name, deployment_id);
+    if request
+        .children()
+        .of_type(apps::StatefulSet)
+        .get(namespace, &statefulset_name)
+        .is_some()
+    {
+        return false;
+    }
+
+    !request
+        .children()
+        .of_type(("batch/v1", "Job"))
+        .get(namespace, &job_name(&statefulset_name))
+        .and_then(|job| job.pointer("/status/succeeded"))
+        .and_then(Value::as_u64)
+        .map_or(false, |succeeded| succeeded > 0)
+}
+
+/// Init containers filling an empty log dir with a replica's archive, the
+/// ordinal in the pod's hostname picks the archive
+pub fn init_containers(restore: &RestoreConfig, s3: &S3Config, version: &str) -> Vec<Value> {
+    let download_command = format!(
+        r#"if ls -A {data} | grep -qv '^lost+found$'; then
+          echo "{data} is not empty, skipping restore"
+          exit 0
+        fi
+        aws s3 cp {url} {restore}/data.tar.gz --endpoint-url {endpoint}"#,
+        data = DATA_PATH,
+        url = backup::object_url(s3, &restore.backup, "${HOSTNAME##*-}"),
+        restore = RESTORE_PATH,
+        endpoint = s3.endpoint,
+    );
+
+    vec![
+        json!({
+            "name": "restore-download",
+            "image": AWS_CLI_IMAGE,
+            "command": ["bash", "-ec", download_command],
+            "env": backup::s3_env(s3),
+            "volumeMounts": [{
+                "mountPath": DATA_PATH,
+                "name": "data",
+                "readOnly": true
+            }, {
+                "mountPath": RESTORE_PATH,
+                "name": "restore"
+            }]
+        }),
+        json!({
+            "name": "restore-extract",
+            "image": format!("{}:{}", NORIA_IMAGE, version),
+            "command": ["bash", "-ec", format!(
+                "[ ! -f {restore}/data.tar.gz ] || tar xzf {restore}/data.tar.gz -C {data}",
+                restore = RESTORE_PATH,
+                data = DATA_PATH,
+            )],
+            "volumeMounts": [{
+                "mountPath": DATA_PATH,
+                "name": "data"
+            }, {
+                "mountPath": RESTORE_PATH,
+                "name": "restore",
+                "readOnly": true
+            }]
+        }),
+    ]
+}
+
+/// Volume holding the downloaded archive until it is extracted
+pub fn volume() -> Value {
+    json!({
+        "name": "restore",
+        "emptyDir": {}
+    })
+}
+
+pub fn create_config<'svc>(
+    server: &server::Config<'svc>,
+    restore: &'svc RestoreConfig,
+) -> Config<'svc> {
+    Config {
+        name: job_name(server.name()),
+        backup: &restore.backup,
+        claims: server.volume_claims(),
+        from_snapshots: restore.s3.is_none(),
+    }
+}
+
+impl<'svc> Builder for Config<'svc> {
+    fn children(self: &Config<'svc>, namespace: &str) -> Vec<Value> {
+        let mut children = vec![];
+
+        // archives are restored by the noria-server pods themselves
+        if !self.from_snapshots {
+            return children;
+        }
+
+        // Restore Job permissions, it creates the claims the StatefulSet adopts
+        children.push(json!({
+            "apiVersion": "v1",
+            "kind": "ServiceAccount",
+            "metadata": {
+                "name": self.name,
+                "namespace": namespace,
+            }
+        }));
+
+        children.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "Role",
+            "metadata": {
+                "name": self.name,
+                "namespace": namespace,
+            },
+            "rules": [{
+                "apiGroups": [""],
+                "resources": ["persistentvolumeclaims"],
+                "verbs": ["get", "create"],
+            }]
+        }));
+
+        children.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "RoleBinding",
+            "metadata": {
+                "name": self.name,
+                "namespace": namespace,
+            },
+            "roleRef": {
+                "apiGroup": "rbac.authorization.k8s.io",
+                "kind": "Role",
+                "name": self.name,
+            },
+            "subjects": [{
+                "kind": "ServiceAccount",
+                "name": self.name,
+                "namespace": namespace,
+            }]
+        }));
+
+        // Each replica's claim is created from its snapshot, unless it exists.
+        // The claims are not children, they outlive the restore.
+        let create_command =
+            self.claims
+                .iter()
+                .enumerate()
+                .fold(String::new(), |mut acc, (replica, claim)| {
+                    let mut claim = claim.clone();
+                    claim["metadata"]["namespace"] = json!(namespace);
+                    claim["spec"]["dataSource"] = json!({
+                        "apiGroup": "snapshot.storage.k8s.io",
+                        "kind": "VolumeSnapshot",
+                        "name": backup::replica_name(self.backup, &replica.to_string()),
+                    });
+                    acc.push_str(&format!(
+                        "kubectl get pvc {} > /dev/null 2>&1 || echo '{}' | kubectl create -f -\n",
+                        claim["metadata"]["name"].as_str().unwrap_or_default(),
+                        claim
+                    ));
+                    acc
+                });
+
+        // Restore Job
+        children.push(json!({
+            "apiVersion": "batch/v1",
+            "kind": "Job",
+            "metadata": {
+                "name": self.name,
+                "namespace": namespace,
+            },
+            "spec": {
+                "backoffLimit": 3,
+                "template": {
+                    "spec": {
+                        "serviceAccountName": self.name,
+                        "restartPolicy": "OnFailure",
+                        "containers": [{
+                            "name": "kubectl",
+                            "image": KUBECTL_IMAGE,
+                            "command": ["bash", "-ec"],
+                            "args": [create_command],
+                        }]
+                    }
+                }
+            }
+        }));
+
+        children
+    }
+}
@@ -0,0 +1,61 @@
+use super::SchedulingConfig;
+
+use roperator::serde_json::{json, Value};
+
+const HOSTNAME_TOPOLOGY_KEY: &str = "kubernetes.io/hostname";
+
+/// Topology spread constraint keeping the pods matching `labels` on distinct
+/// nodes, as long as there are enough of them. A single-node cluster still
+/// schedules every pod.
+pub fn spread_over_nodes(labels: Value) -> Value {
+    json!({
+        "maxSkew": 1,
+        "topologyKey": HOSTNAME_TOPOLOGY_KEY,
+        "whenUnsatisfiable": "DoNotSchedule",
+        "labelSelector": {
+            "matchLabels": labels
+        }
+    })
+}
+
+/// Sets the scheduling fields of the spec on a pod spec. `default_spread`
+/// applies unless the spec brings its own affinity or topology spread
+/// constraints.
+pub fn apply(
+    pod: &mut Value,
+    scheduling: Option<&SchedulingConfig>,
+    default_spread: Option<Value>,
+) {
+    let custom_placement = match scheduling {
+        Some(SchedulingConfig {
+            affinity,
+            topology_spread_constraints,
+            ..
+        }) => affinity.is_some() || topology_spread_constraints.is_some(),
+        None => false,
+    };
+
+    if !custom_placement {
+        if let Some(spread) = default_spread {
+            pod["topologySpreadConstraints"] = json!([spread]);
+        }
+    }
+
+    if let Some(scheduling) = scheduling {
+        if let Some(node_selector) = &scheduling.node_selector {
+            pod["nodeSelector"] = json!(node_selector);
+        }
+        if let Some(tolerations) = &scheduling.tolerations {
+            pod["tolerations"] = json!(tolerations);
+        }
+        if let Some(affinity) = &scheduling.affinity {
+            pod["affinity"] = affinity.clone();
+        }
+        if let Some(constraints) = &scheduling.topology_spread_constraints {
+            pod["topologySpreadConstraints"] = json!(constraints);
+        }
+        if let Some(priority_class_name) = &scheduling.priority_class_name {
+            pod["priorityClassName"] = json!(priority_class_name);
+        }
+    }
+}
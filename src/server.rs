@@ -1,28 +1,99 @@
+use super::disruption;
+use super::monitoring;
+use super::resources::{self, DEFAULT_MEMORY_MULTIPLIER};
+use super::restore;
+use super::scheduling;
+use super::storage;
 use super::Builder;
-use super::NoriaServerConfig;
-use super::{DEFAULT_NORIA_VERSION, NORIA_IMAGE, ZOOKEEPER_CLIENT_SERVICE_NAME};
+use super::{MonitoringConfig, NoriaServerConfig, RestoreConfig, SchedulingConfig, StorageConfig};
+use super::{DEFAULT_NORIA_VERSION, NORIA_IMAGE};
 
 use roperator::serde_json::{json, Value};
 
 pub struct Config<'svc> {
     id: &'svc str,
     name: String,
+    noria_name: &'svc str,
+    instance: String,
+    zookeeper_address: &'svc str,
     version: &'svc str,
     max_heap: u64,
+    resources: Value,
+    scheduling: Option<&'svc SchedulingConfig>,
     storage_size: u64,
+    storage: Option<&'svc StorageConfig>,
     replicas: usize,
+    max_unavailable: Option<usize>,
+    disruption_budget: Option<Value>,
+    paused: bool,
+    restore: Option<&'svc RestoreConfig>,
+    monitoring: Option<&'svc MonitoringConfig>,
 }
 
 // Default Noria Server settings
 const DEFAULT_NORIA_SERVER_MAX_HEAP: u64 = 96;
-const DEFAULT_NORIA_SERVER_STORAGE_SIZE: u64 = 1024;
-const DEFAULT_NORIA_SERVER_REPLICAS: usize = 3;
+pub const DEFAULT_NORIA_SERVER_STORAGE_SIZE: u64 = 1024;
+pub const DEFAULT_NORIA_SERVER_REPLICAS: usize = 3;
+
+/// Set to "true" on a Noria resource to hold back noria-server rollouts
+pub const PAUSE_ROLLOUT_ANNOTATION: &str = "noria-operator.io/pause-rollout";
+
+// A pod is ready once the controller lists it among its healthy workers. Only
+// the leader answers, so every pod behind the headless Service is asked,
+// including those that aren't ready yet, like the leader before the quorum is
+// complete.
+fn readiness_script(nodes_service_name: &str) -> String {
+    format!(
+        r#"
+import os, socket, sys, urllib.request
+me = ("//" + os.environ["NODE_IP"] + ":").encode()
+try:
+    pods = set(info[4][0] for info in
+               socket.getaddrinfo("{service}", 6033, proto=socket.IPPROTO_TCP))
+except OSError:
+    sys.exit(1)
+for pod in pods:
+    try:
+        workers = urllib.request.urlopen("http://%s:6033/healthy_workers" % pod, timeout=2).read()
+    except Exception:
+        continue
+    sys.exit(0 if me in workers else 1)
+sys.exit(1)
+"#,
+        service = nodes_service_name
+    )
+}
+
+pub fn name(noria_name: &str, deployment_id: &str) -> String {
+    format!("noria-server-{}-{}", noria_name, deployment_id)
+}
+
+/// PersistentVolumeClaim the StatefulSet creates for a replica's logs
+pub fn claim_name(statefulset_name: &str, replica: usize) -> String {
+    format!("data-{}-{}", statefulset_name, replica)
+}
+
+/// Whether the deployment runs noria-server, unless `enabled: false`
+pub fn enabled(noria_server: &Option<NoriaServerConfig>) -> bool {
+    match noria_server {
+        Some(NoriaServerConfig {
+            enabled: Some(enabled),
+            ..
+        }) => *enabled,
+        _ => true,
+    }
+}
 
 pub fn create_config<'svc>(
+    noria_name: &'svc str,
     noria_server: &'svc Option<NoriaServerConfig>,
     deployment_id: &'svc str,
+    zookeeper_address: &'svc str,
+    paused: bool,
+    restore: Option<&'svc RestoreConfig>,
+    monitoring: Option<&'svc MonitoringConfig>,
 ) -> Config<'svc> {
-    let noria_server_name = format!("noria-server-{}", deployment_id);
+    let noria_server_name = name(noria_name, deployment_id);
 
     let noria_server_max_heap = match noria_server {
         Some(NoriaServerConfig {
@@ -31,6 +102,41 @@ pub fn create_config<'svc>(
         _ => DEFAULT_NORIA_SERVER_MAX_HEAP,
     };
 
+    let noria_server_memory_multiplier = match noria_server {
+        Some(NoriaServerConfig {
+            memory_multiplier: Some(m),
+            ..
+        }) => *m,
+        _ => DEFAULT_MEMORY_MULTIPLIER,
+    };
+
+    let noria_server_resources = match noria_server {
+        Some(NoriaServerConfig { resources, .. }) => resources,
+        None => &None,
+    };
+
+    // --memory follows an explicit memory limit
+    let noria_server_max_heap = resources::heap_size(
+        noria_server_max_heap,
+        noria_server_memory_multiplier,
+        noria_server_resources,
+    );
+
+    let noria_server_scheduling = match noria_server {
+        Some(NoriaServerConfig {
+            scheduling: Some(s),
+            ..
+        }) => Some(s),
+        _ => None,
+    };
+
+    let noria_server_storage = match noria_server {
+        Some(NoriaServerConfig {
+            storage: Some(s), ..
+        }) => Some(s),
+        _ => None,
+    };
+
     let noria_server_storage_size = match noria_server {
         Some(NoriaServerConfig {
             storage_size: Some(m),
@@ -53,13 +159,71 @@ pub fn create_config<'svc>(
         _ => DEFAULT_NORIA_VERSION,
     };
 
+    let noria_server_max_unavailable = match noria_server {
+        Some(NoriaServerConfig {
+            max_unavailable: Some(n),
+            ..
+        }) => Some(*n),
+        _ => None,
+    };
+
+    // Voluntary evictions take one worker at a time, unless the spec says otherwise
+    let noria_server_disruption_budget = match noria_server {
+        Some(NoriaServerConfig {
+            disruption_budget, ..
+        }) => disruption::budget(disruption_budget.as_ref(), true),
+        None => disruption::budget(None, true),
+    };
+
     Config {
         id: deployment_id,
         name: noria_server_name,
+        noria_name,
+        instance: format!("{}-{}", noria_name, deployment_id),
+        zookeeper_address,
         version: noria_server_version,
         max_heap: noria_server_max_heap,
+        resources: resources::container_resources(
+            Some(noria_server_max_heap),
+            noria_server_memory_multiplier,
+            noria_server_resources,
+            json!({}),
+        ),
+        scheduling: noria_server_scheduling,
         storage_size: noria_server_storage_size,
+        storage: noria_server_storage,
         replicas: noria_server_replicas,
+        max_unavailable: noria_server_max_unavailable,
+        disruption_budget: noria_server_disruption_budget,
+        paused,
+        restore,
+        monitoring,
+    }
+}
+
+impl<'svc> Config<'svc> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Claims of every replica, as the StatefulSet creates them from its template
+    pub fn volume_claims(&self) -> Vec<Value> {
+        (0..self.replicas)
+            .map(|replica| {
+                let mut claim =
+                    storage::volume_claim_template("data", self.storage_size, self.storage);
+                claim["apiVersion"] = json!("v1");
+                claim["kind"] = json!("PersistentVolumeClaim");
+                claim["metadata"] = json!({
+                    "name": claim_name(&self.name, replica),
+                    "labels": {
+                        "noria-operator.io/kind": "noria-server",
+                        "noria-operator.io/name": self.instance,
+                    }
+                });
+                claim
+            })
+            .collect()
     }
 }
 
@@ -82,37 +246,78 @@ impl<'svc> Builder for Config<'svc> {
                 }],
                 "selector": {
                     "noria-operator.io/kind": "noria-server",
-                    "noria-operator.io/name": self.id
+                    "noria-operator.io/name": self.instance
+                }
+            }
+        }));
+
+        // Noria-server headless Service, resolving to every pod whether it is
+        // ready or not
+        let nodes_service_name = format!("{}-nodes", self.name);
+        children.push(json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": {
+                "name": nodes_service_name,
+                "namespace": namespace,
+            },
+            "spec": {
+                "clusterIP": "None",
+                "publishNotReadyAddresses": true,
+                "ports": [{
+                    "port": 6033,
+                    "name": "noria",
+                    "targetPort": 6033,
+                }],
+                "selector": {
+                    "noria-operator.io/kind": "noria-server",
+                    "noria-operator.io/name": self.instance
                 }
             }
         }));
 
-        let noria_server_docker_mem = (1.3 * (self.max_heap as f32)) as u64;
         let noria_server_command = format!(
             r#"/usr/local/bin/noria-server --address $NODE_IP \
               --deployment {} --log-dir /var/lib/noria --memory {} \
-              --quorum {} --shards 0 --zookeeper {}:2181"#,
+              --quorum {} --shards 0 --zookeeper {}"#,
             self.id,
             (self.max_heap * 1024 * 1024).to_string(),
             self.replicas.to_string(),
-            ZOOKEEPER_CLIENT_SERVICE_NAME
+            self.zookeeper_address
         );
 
+        // Pods are replaced one at a time, each waiting for the previous to be
+        // ready. A partition covering every pod holds the rollout back.
+        let mut rolling_update = json!({
+            "partition": if self.paused { self.replicas } else { 0 },
+        });
+        if let Some(max_unavailable) = self.max_unavailable {
+            rolling_update["maxUnavailable"] = json!(max_unavailable);
+        }
+
         // Noria-Server StatefulSet
-        children.push(json!({
+        let mut statefulset = json!({
             "apiVersion": "apps/v1",
             "kind": "StatefulSet",
             "metadata": {
                 "name": self.name,
                 "namespace": namespace,
+                "labels": {
+                    "noria-operator.io/kind": "noria-server",
+                    "noria-operator.io/name": self.instance,
+                    "noria-operator.io/deployment": self.id
+                }
             },
             "spec": {
                 "replicas": self.replicas,
-                "serviceName": self.name,
+                "serviceName": nodes_service_name,
+                // The controller waits for a quorum of workers, so no pod is
+                // ready before all of them run
+                "podManagementPolicy": "Parallel",
                 "selector": {
                     "matchLabels": {
                         "noria-operator.io/kind": "noria-server",
-                        "noria-operator.io/name": self.id,
+                        "noria-operator.io/name": self.instance,
                     }
                 },
                 "template": {
@@ -120,7 +325,7 @@ impl<'svc> Builder for Config<'svc> {
                         "name": self.name,
                         "labels": {
                             "noria-operator.io/kind": "noria-server",
-                            "noria-operator.io/name": self.id
+                            "noria-operator.io/name": self.instance
                         }
                     },
                     "spec": {
@@ -147,13 +352,20 @@ impl<'svc> Builder for Config<'svc> {
                                 "name": "api",
                                 "protocol": "TCP",
                             }],
-                            "resources": {
-                                "limits": {
-                                    "memory": format!("{}Mi", noria_server_docker_mem)
+                            "resources": self.resources,
+                            "readinessProbe": {
+                                "exec": {
+                                    "command": [
+                                        "python3",
+                                        "-c",
+                                        readiness_script(&nodes_service_name),
+                                    ]
                                 },
-                                "requests": {
-                                    "memory": format!("{}Mi", noria_server_docker_mem)
-                                }
+                                "failureThreshold": 3,
+                                "initialDelaySeconds": 10,
+                                "periodSeconds": 10,
+                                "successThreshold": 1,
+                                "timeoutSeconds": 15,
                             },
                             "livenessProbe": {
                                 "tcpSocket": {
@@ -173,23 +385,73 @@ impl<'svc> Builder for Config<'svc> {
                     }
                 },
                 "updateStrategy": {
-                    "type": "OnDelete"
+                    "type": "RollingUpdate",
+                    "rollingUpdate": rolling_update
                 },
-                "volumeClaimTemplates": [{
-                    "metadata": {
-                        "name": "data"
-                    },
-                    "spec": {
-                        "accessModes": ["ReadWriteOnce"],
-                        "resources": {
-                            "requests": {
-                                "storage": format!("{}Gi", self.storage_size / 1024 as u64)
-                            }
-                        }
-                    }
-                }]
+                "volumeClaimTemplates": [
+                    storage::volume_claim_template("data", self.storage_size, self.storage)
+                ]
             }
-        }));
+        });
+
+        if let Some(policy) = storage::retention_policy(self.storage) {
+            statefulset["spec"]["persistentVolumeClaimRetentionPolicy"] = policy;
+        }
+
+        // New volumes are seeded from the backup's archives before noria-server starts
+        if let Some(restore @ RestoreConfig { s3: Some(s3), .. }) = self.restore {
+            let pod = &mut statefulset["spec"]["template"]["spec"];
+            pod["initContainers"] = json!(restore::init_containers(restore, s3, self.version));
+            pod["volumes"] = json!([restore::volume()]);
+        }
+
+        // Spread over nodes, so that losing a node keeps a quorum of workers
+        scheduling::apply(
+            &mut statefulset["spec"]["template"]["spec"],
+            self.scheduling,
+            Some(scheduling::spread_over_nodes(json!({
+                "noria-operator.io/kind": "noria-server",
+                "noria-operator.io/name": self.instance,
+            }))),
+        );
+
+        // The exporter next to noria-server reads the controller's graph and
+        // statistics, it only reports them from the leader's pod
+        if let Some(monitoring) = self.monitoring {
+            if let Some(containers) =
+                statefulset["spec"]["template"]["spec"]["containers"].as_array_mut()
+            {
+                containers.push(monitoring::server_exporter(self.version));
+            }
+
+            children.append(&mut monitoring::children(
+                monitoring,
+                &self.name,
+                namespace,
+                json!({
+                    "noria-operator.io/kind": "noria-server",
+                    "noria-operator.io/name": self.instance,
+                }),
+                self.noria_name,
+                Some(self.id),
+                monitoring::METRICS_PORT,
+            ));
+        }
+
+        children.push(statefulset);
+
+        // Noria-Server PodDisruptionBudget
+        if let Some(budget) = &self.disruption_budget {
+            children.push(disruption::pod_disruption_budget(
+                &self.name,
+                namespace,
+                json!({
+                    "noria-operator.io/kind": "noria-server",
+                    "noria-operator.io/name": self.instance,
+                }),
+                budget.clone(),
+            ));
+        }
 
         children
     }
@@ -0,0 +1,285 @@
+use super::backup;
+use super::Builder;
+use super::{S3Config, ZookeeperBackupConfig, ZookeeperRestoreConfig};
+use super::{AWS_CLI_IMAGE, CONFLUENT_ZOOKEEPER_IMAGE};
+
+use roperator::serde_json::{json, Value};
+
+const DEFAULT_RETENTION: usize = 7;
+
+const DATA_PATH: &str = "/var/lib/zookeeper";
+// Claim holding the archives, when they are not kept in an object store
+const BACKUPS_PATH: &str = "/backups";
+// Archive on its way to or from an object store
+const ARCHIVE_PATH: &str = "/archive";
+
+pub struct Config<'zk> {
+    name: String,
+    noria_name: &'zk str,
+    version: &'zk str,
+    schedule: &'zk str,
+    retention: usize,
+    claim_name: Option<&'zk str>,
+    s3: Option<&'zk S3Config>,
+}
+
+// Archives are named after the ensemble and the time of the export, so that
+// they sort from oldest to newest
+fn archive_prefix(noria_name: &str) -> String {
+    format!("zookeeper-{}-", noria_name)
+}
+
+pub fn create_config<'zk>(
+    noria_name: &'zk str,
+    backup: &'zk ZookeeperBackupConfig,
+    version: &'zk str,
+) -> Config<'zk> {
+    Config {
+        name: format!("zookeeper-{}-backup", noria_name),
+        noria_name,
+        version,
+        schedule: &backup.schedule,
+        retention: backup.retention.unwrap_or(DEFAULT_RETENTION),
+        claim_name: backup.claim_name.as_deref(),
+        s3: backup.s3.as_ref(),
+    }
+}
+
+/// Init containers filling the data dir of a new member with the snapshot,
+/// they run before the member's id is written
+pub fn init_containers(restore: &ZookeeperRestoreConfig, version: &str) -> Vec<Value> {
+    // a member that has data already is left alone
+    let skip_existing = format!(
+        r#"if [ -d {data}/version-2 ]; then
+          echo "{data} holds data already, skipping restore"
+          exit 0
+        fi"#,
+        data = DATA_PATH,
+    );
+
+    let (source, mut init_containers) = match &restore.s3 {
+        Some(s3) => (
+            ARCHIVE_PATH,
+            vec![json!({
+                "name": "restore-download",
+                "image": AWS_CLI_IMAGE,
+                "command": ["bash", "-ec", format!(
+                    r#"{}
+                    aws s3 cp {}{} {}/{} --endpoint-url {}"#,
+                    skip_existing,
+                    backup::prefix_url(s3),
+                    restore.snapshot,
+                    ARCHIVE_PATH,
+                    restore.snapshot,
+                    s3.endpoint,
+                )],
+                "env": backup::s3_env(s3),
+                "volumeMounts": [{
+                    "mountPath": DATA_PATH,
+                    "name": "data",
+                    "readOnly": true
+                }, {
+                    "mountPath": ARCHIVE_PATH,
+                    "name": "snapshot"
+                }]
+            })],
+        ),
+        None => (BACKUPS_PATH, vec![]),
+    };
+
+    init_containers.push(json!({
+        "name": "restore-snapshot",
+        "image": format!("{}:{}", CONFLUENT_ZOOKEEPER_IMAGE, version),
+        "command": ["bash", "-ec", format!(
+            r#"{}
+            tar xzf {}/{} -C {}"#,
+            skip_existing, source, restore.snapshot, DATA_PATH,
+        )],
+        "volumeMounts": [{
+            "mountPath": DATA_PATH,
+            "name": "data"
+        }, {
+            "mountPath": source,
+            "name": "snapshot",
+            "readOnly": true
+        }]
+    }));
+
+    init_containers
+}
+
+/// Volume the snapshot is restored from
+pub fn volume(restore: &ZookeeperRestoreConfig) -> Value {
+    match &restore.claim_name {
+        Some(claim_name) => json!({
+            "name": "snapshot",
+            "persistentVolumeClaim": {
+                "claimName": claim_name,
+                "readOnly": true
+            }
+        }),
+        None => json!({
+            "name": "snapshot",
+            "emptyDir": {}
+        }),
+    }
+}
+
+impl<'zk> Builder for Config<'zk> {
+    fn children(self: &Config<'zk>, namespace: &str) -> Vec<Value> {
+        let prefix = archive_prefix(self.noria_name);
+        let statefulset_name = format!("zookeeper-{}", self.noria_name);
+
+        // The first member's data dir holds the latest snapshot and the
+        // transaction logs that followed it, archiving both keeps every write
+        let archive_command = |target: &str| {
+            format!(
+                r#"archive={prefix}$(date -u +%Y%m%d%H%M%S).tar.gz
+                tar czf {target}/$archive.tmp -C {data} version-2
+                mv {target}/$archive.tmp {target}/$archive"#,
+                prefix = prefix,
+                target = target,
+                data = DATA_PATH,
+            )
+        };
+
+        // The oldest archives beyond the retention count are removed
+        let (mut containers, mut volumes) = match (self.claim_name, self.s3) {
+            (Some(claim_name), _) => (
+                vec![json!({
+                    "name": "archive",
+                    "image": format!("{}:{}", CONFLUENT_ZOOKEEPER_IMAGE, self.version),
+                    "command": ["bash", "-ec", format!(
+                        r#"{}
+                        ls -1 {backups}/{prefix}*.tar.gz | sort | head -n -{retention} \
+                          | xargs -r rm --"#,
+                        archive_command(BACKUPS_PATH),
+                        backups = BACKUPS_PATH,
+                        prefix = prefix,
+                        retention = self.retention,
+                    )],
+                    "volumeMounts": [{
+                        "mountPath": BACKUPS_PATH,
+                        "name": "backups"
+                    }]
+                })],
+                vec![json!({
+                    "name": "backups",
+                    "persistentVolumeClaim": {
+                        "claimName": claim_name
+                    }
+                })],
+            ),
+            (None, Some(s3)) => (
+                vec![json!({
+                    "name": "upload",
+                    "image": AWS_CLI_IMAGE,
+                    "command": ["bash", "-ec", format!(
+                        r#"for archive in {archive}/*.tar.gz; do
+                          aws s3 cp "$archive" {url}$(basename "$archive") --endpoint-url {endpoint}
+                        done
+                        aws s3 ls {url}{prefix} --endpoint-url {endpoint} | awk '{{print $4}}' \
+                          | sort | head -n -{retention} | while read -r archive; do
+                          aws s3 rm {url}$archive --endpoint-url {endpoint}
+                        done"#,
+                        archive = ARCHIVE_PATH,
+                        url = backup::prefix_url(s3),
+                        prefix = prefix,
+                        endpoint = s3.endpoint,
+                        retention = self.retention,
+                    )],
+                    "env": backup::s3_env(s3),
+                    "volumeMounts": [{
+                        "mountPath": ARCHIVE_PATH,
+                        "name": "archive",
+                        "readOnly": true
+                    }]
+                })],
+                vec![json!({
+                    "name": "archive",
+                    "emptyDir": {}
+                })],
+            ),
+            (None, None) => return vec![],
+        };
+
+        let mut init_containers = vec![];
+        if self.s3.is_some() {
+            init_containers.push(json!({
+                "name": "archive",
+                "image": format!("{}:{}", CONFLUENT_ZOOKEEPER_IMAGE, self.version),
+                "command": ["bash", "-ec", archive_command(ARCHIVE_PATH)],
+                "volumeMounts": [{
+                    "mountPath": DATA_PATH,
+                    "name": "data",
+                    "readOnly": true
+                }, {
+                    "mountPath": ARCHIVE_PATH,
+                    "name": "archive"
+                }]
+            }));
+        } else if let Some(volume_mounts) = containers[0]["volumeMounts"].as_array_mut() {
+            volume_mounts.push(json!({
+                "mountPath": DATA_PATH,
+                "name": "data",
+                "readOnly": true
+            }));
+        }
+
+        volumes.push(json!({
+            "name": "data",
+            "persistentVolumeClaim": {
+                "claimName": format!("data-{}-0", statefulset_name),
+                "readOnly": true
+            }
+        }));
+
+        // Zookeeper snapshot CronJob, next to the first member that holds the volume
+        vec![json!({
+            "apiVersion": "batch/v1",
+            "kind": "CronJob",
+            "metadata": {
+                "name": self.name,
+                "namespace": namespace,
+            },
+            "spec": {
+                "schedule": self.schedule,
+                "concurrencyPolicy": "Forbid",
+                "successfulJobsHistoryLimit": 1,
+                "failedJobsHistoryLimit": 3,
+                "jobTemplate": {
+                    "spec": {
+                        "backoffLimit": 2,
+                        "template": {
+                            "metadata": {
+                                "labels": {
+                                    "noria-operator.io/kind": "zookeeper-backup",
+                                    "noria-operator.io/name": self.noria_name
+                                }
+                            },
+                            "spec": {
+                                "restartPolicy": "OnFailure",
+                                "affinity": {
+                                    "podAffinity": {
+                                        "requiredDuringSchedulingIgnoredDuringExecution": [{
+                                            "labelSelector": {
+                                                "matchLabels": {
+                                                    "statefulset.kubernetes.io/pod-name":
+                                                        format!("{}-0", statefulset_name)
+                                                }
+                                            },
+                                            "topologyKey": "kubernetes.io/hostname"
+                                        }]
+                                    }
+                                },
+                                "initContainers": init_containers,
+                                "containers": containers,
+                                "volumes": volumes,
+                            }
+                        }
+                    }
+                }
+            }
+        })]
+    }
+}
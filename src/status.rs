@@ -0,0 +1,412 @@
+use super::recipe::RECIPE_HASH_ANNOTATION;
+
+use roperator::prelude::*;
+use roperator::serde_json::{json, Value};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+const COMPONENT_LABEL: &str = "noria-operator.io/kind";
+const DEPLOYMENT_LABEL: &str = "noria-operator.io/deployment";
+const TARGET_REPLICAS_ANNOTATION: &str = "noria-operator.io/target-replicas";
+const UPDATE_ACTION_ANNOTATION: &str = "noria-operator.io/update-action";
+const QUORUM_TLS_ANNOTATION: &str = "noria-operator.io/quorum-tls";
+
+// A rollout that has not made progress for this long is reported as halted
+const PROGRESS_DEADLINE_SECONDS: i64 = 600;
+
+#[derive(PartialEq)]
+enum Health {
+    Ready,
+    Progressing,
+    Degraded,
+}
+
+struct Workload {
+    component: String,
+    deployment: Option<String>,
+    health: Health,
+    message: String,
+    status: Value,
+}
+
+// Status reported for a workload by the previous sync
+fn previous_status<'a>(request: &'a SyncRequest, name: &str) -> Option<&'a Value> {
+    let components = request.parent.pointer("/status/components")?.as_object()?;
+    let deployments = components
+        .get("deployments")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object)
+        .flat_map(|deployment| deployment.values());
+
+    components
+        .values()
+        .chain(deployments)
+        .find(|status| status["name"] == name)
+}
+
+// Compares a desired StatefulSet or Deployment with its observed state
+fn workload_status(request: &SyncRequest, child: &Value) -> Option<Workload> {
+    let api_version = child["apiVersion"].as_str()?;
+    let kind = child["kind"].as_str()?;
+    if kind != "StatefulSet" && kind != "Deployment" {
+        return None;
+    }
+
+    let name = child["metadata"]["name"].as_str()?;
+    let namespace = child["metadata"]["namespace"].as_str()?;
+    let labels = &child["metadata"]["labels"];
+
+    let existing = request
+        .children()
+        .of_type((api_version, kind))
+        .get(namespace, name);
+
+    // an autoscaled Deployment leaves its replicas to the autoscaler
+    let replicas = child["spec"]["replicas"]
+        .as_u64()
+        .or_else(|| existing.and_then(|e| e.pointer("/spec/replicas")?.as_u64()))
+        .unwrap_or(1);
+    let target_replicas = child["metadata"]["annotations"][TARGET_REPLICAS_ANNOTATION]
+        .as_str()
+        .and_then(|target| target.parse::<u64>().ok())
+        .unwrap_or(replicas);
+    let update_action = child["metadata"]["annotations"][UPDATE_ACTION_ANNOTATION]
+        .as_str()
+        .unwrap_or("in-place");
+    let quorum_tls = child["metadata"]["annotations"][QUORUM_TLS_ANNOTATION].as_str();
+    let paused = child
+        .pointer("/spec/updateStrategy/rollingUpdate/partition")
+        .and_then(Value::as_u64)
+        .unwrap_or(0)
+        > 0;
+
+    let (ready, updated, current) = match existing {
+        Some(existing) => {
+            let field = |path: &str| existing.pointer(path).and_then(Value::as_u64);
+            (
+                field("/status/readyReplicas").unwrap_or(0),
+                field("/status/updatedReplicas").unwrap_or(0),
+                field("/status/observedGeneration") >= field("/metadata/generation"),
+            )
+        }
+        None => (0, 0, false),
+    };
+
+    // progress is any change in the number of updated or ready replicas
+    let now = Utc::now();
+    let last_progress_time = previous_status(request, name)
+        .filter(|previous| previous["readyReplicas"] == ready)
+        .filter(|previous| previous["updatedReplicas"] == updated)
+        .and_then(|previous| previous["lastProgressTime"].as_str())
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or(now);
+    let stalled = (now - last_progress_time).num_seconds() > PROGRESS_DEADLINE_SECONDS;
+
+    let (health, message) = if update_action != "in-place" {
+        (
+            Health::Progressing,
+            format!("recreating {} ({})", name, update_action),
+        )
+    } else if target_replicas != replicas {
+        (
+            Health::Progressing,
+            format!(
+                "scaling {} from {} to {} replicas",
+                name, replicas, target_replicas
+            ),
+        )
+    } else if let Some(phase) = quorum_tls.filter(|phase| *phase != "encrypted") {
+        (
+            Health::Progressing,
+            format!(
+                "switching quorum of {} to TLS ({}), {}/{} replicas updated",
+                name, phase, updated, replicas
+            ),
+        )
+    } else if current && ready >= replicas {
+        (Health::Ready, String::new())
+    } else if paused {
+        (
+            Health::Progressing,
+            format!(
+                "rollout of {} paused, {}/{} replicas updated",
+                name, updated, replicas
+            ),
+        )
+    } else if (!current || updated < replicas) && !stalled {
+        (
+            Health::Progressing,
+            format!("{}/{} replicas of {} ready", ready, replicas, name),
+        )
+    } else if updated < replicas {
+        (
+            Health::Degraded,
+            format!(
+                "rollout of {} halted, {}/{} replicas updated and {} ready",
+                name, updated, replicas, ready
+            ),
+        )
+    } else {
+        (
+            Health::Degraded,
+            format!("{}/{} replicas of {} ready", ready, replicas, name),
+        )
+    };
+
+    let mut status = json!({
+        "kind": kind,
+        "name": name,
+        "replicas": replicas,
+        "targetReplicas": target_replicas,
+        "readyReplicas": ready,
+        "updatedReplicas": updated,
+        "ready": health == Health::Ready,
+        "paused": paused,
+        "updateAction": update_action,
+        "lastProgressTime": last_progress_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+    });
+    if let Some(phase) = quorum_tls {
+        status["quorumTls"] = json!(phase);
+    }
+
+    Some(Workload {
+        component: labels[COMPONENT_LABEL].as_str()?.to_string(),
+        deployment: labels[DEPLOYMENT_LABEL].as_str().map(str::to_string),
+        status,
+        health,
+        message,
+    })
+}
+
+// Address of a noria-mysql Service exposed outside the cluster, as assigned
+// by the cluster. A load balancer address stays null until it is provisioned.
+fn external_endpoint(request: &SyncRequest, child: &Value) -> Option<(String, Value)> {
+    if child["kind"] != "Service" || child["metadata"]["labels"][COMPONENT_LABEL] != "noria-mysql" {
+        return None;
+    }
+
+    let service_type = child["spec"]["type"].as_str()?;
+    if service_type != "NodePort" && service_type != "LoadBalancer" {
+        return None;
+    }
+
+    let id = child["metadata"]["labels"][DEPLOYMENT_LABEL].as_str()?;
+    let name = child["metadata"]["name"].as_str()?;
+    let namespace = child["metadata"]["namespace"].as_str()?;
+
+    let existing = request
+        .children()
+        .of_type(("v1", "Service"))
+        .get(namespace, name);
+
+    let endpoint = match (service_type, existing) {
+        ("NodePort", Some(existing)) => json!({
+            "type": service_type,
+            "nodePort": existing.pointer("/spec/ports/0/nodePort"),
+        }),
+        ("LoadBalancer", Some(existing)) => {
+            let ingress = existing.pointer("/status/loadBalancer/ingress/0");
+            let host = ingress.and_then(|ingress| {
+                ingress["ip"]
+                    .as_str()
+                    .or_else(|| ingress["hostname"].as_str())
+            });
+            json!({
+                "type": service_type,
+                "address": host.map(|host| format!("{}:3306", host)),
+            })
+        }
+        _ => json!({ "type": service_type }),
+    };
+
+    Some((id.to_string(), endpoint))
+}
+
+// Hash of the recipe applied by a recipe Job, once the Job has succeeded
+fn applied_recipe(request: &SyncRequest, child: &Value) -> Option<(String, Value)> {
+    if child["kind"] != "Job" || child["metadata"]["labels"][COMPONENT_LABEL] != "noria-recipe" {
+        return None;
+    }
+
+    let id = child["metadata"]["labels"][DEPLOYMENT_LABEL].as_str()?;
+    let name = child["metadata"]["name"].as_str()?;
+    let namespace = child["metadata"]["namespace"].as_str()?;
+
+    let existing = request
+        .children()
+        .of_type(("batch/v1", "Job"))
+        .get(namespace, name)?;
+
+    let succeeded = existing
+        .pointer("/status/succeeded")
+        .and_then(Value::as_u64);
+    if succeeded.unwrap_or(0) == 0 {
+        return None;
+    }
+
+    Some((
+        id.to_string(),
+        child["metadata"]["annotations"][RECIPE_HASH_ANNOTATION].clone(),
+    ))
+}
+
+// Keeps the previous transition time if the condition did not change
+fn condition(
+    request: &SyncRequest,
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: &str,
+) -> Value {
+    let status = if status { "True" } else { "False" };
+
+    let last_transition_time = request
+        .parent
+        .pointer("/status/conditions")
+        .and_then(Value::as_array)
+        .and_then(|conditions| {
+            conditions
+                .iter()
+                .find(|c| c["type"] == type_ && c["status"] == status)
+        })
+        .and_then(|c| c["lastTransitionTime"].as_str())
+        .map(str::to_string)
+        .unwrap_or_else(|| Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+
+    json!({
+        "type": type_,
+        "status": status,
+        "reason": reason,
+        "message": message,
+        "lastTransitionTime": last_transition_time,
+    })
+}
+
+fn observed_generation(request: &SyncRequest) -> Value {
+    request
+        .parent
+        .pointer("/metadata/generation")
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+/// Status of a Noria, from its children and the deployments whose recipe Job
+/// ran out of retries
+pub fn create_status(
+    request: &SyncRequest,
+    children: &[Value],
+    failed_recipes: &[String],
+) -> Value {
+    let workloads = children
+        .iter()
+        .filter_map(|child| workload_status(request, child))
+        .collect::<Vec<Workload>>();
+
+    let recipe_messages = failed_recipes
+        .iter()
+        .map(|id| format!("recipe of deployment {} failed, retrying", id))
+        .collect::<Vec<String>>();
+
+    let not_ready = workloads
+        .iter()
+        .filter(|w| w.health != Health::Ready)
+        .map(|w| w.message.as_str())
+        .chain(recipe_messages.iter().map(String::as_str))
+        .collect::<Vec<&str>>();
+
+    let ready = not_ready.is_empty();
+    let ready_reason = if ready {
+        "AllReplicasReady"
+    } else {
+        "ReplicasNotReady"
+    };
+    let progressing = workloads.iter().any(|w| w.health == Health::Progressing);
+    let progressing_reason = if progressing {
+        "RolloutInProgress"
+    } else {
+        "RolloutComplete"
+    };
+    let degraded =
+        workloads.iter().any(|w| w.health == Health::Degraded) || !failed_recipes.is_empty();
+    let degraded_reason = if workloads.iter().any(|w| w.health == Health::Degraded) {
+        "ReplicasUnavailable"
+    } else if !failed_recipes.is_empty() {
+        "RecipeFailed"
+    } else {
+        "AsExpected"
+    };
+
+    let (phase, message) = if ready {
+        ("Running", String::from("Sync complete"))
+    } else if degraded {
+        ("Degraded", not_ready.join(", "))
+    } else {
+        ("Pending", not_ready.join(", "))
+    };
+
+    let mut deployments: Vec<Value> = vec![];
+    let mut components = json!({});
+    for workload in workloads {
+        match workload.deployment {
+            Some(id) => {
+                let index = match deployments.iter().position(|d| d["id"] == id.as_str()) {
+                    Some(index) => index,
+                    None => {
+                        deployments.push(json!({ "id": id }));
+                        deployments.len() - 1
+                    }
+                };
+                deployments[index][workload.component] = workload.status;
+            }
+            None => components[workload.component] = workload.status,
+        }
+    }
+
+    for (id, hash) in children
+        .iter()
+        .filter_map(|child| applied_recipe(request, child))
+    {
+        if let Some(deployment) = deployments.iter_mut().find(|d| d["id"] == id.as_str()) {
+            deployment["appliedRecipe"] = hash;
+        }
+    }
+
+    for (id, endpoint) in children
+        .iter()
+        .filter_map(|child| external_endpoint(request, child))
+    {
+        if let Some(deployment) = deployments.iter_mut().find(|d| d["id"] == id.as_str()) {
+            deployment["mysqlEndpoint"] = endpoint;
+        }
+    }
+
+    components["deployments"] = Value::Array(deployments);
+
+    json!({
+        "phase": phase,
+        "message": message,
+        "observedGeneration": observed_generation(request),
+        "components": components,
+        "conditions": [
+            condition(request, "Ready", ready, ready_reason, &message),
+            condition(request, "Progressing", progressing, progressing_reason, &message),
+            condition(request, "Degraded", degraded, degraded_reason, &message),
+        ],
+    })
+}
+
+pub fn create_error_status(request: &SyncRequest, message: &str) -> Value {
+    json!({
+        "phase": "Error",
+        "message": message,
+        "observedGeneration": observed_generation(request),
+        "conditions": [
+            condition(request, "Ready", false, "SyncError", message),
+            condition(request, "Progressing", false, "SyncError", message),
+            condition(request, "Degraded", true, "SyncError", message),
+        ],
+    })
+}
@@ -0,0 +1,90 @@
+use super::StorageConfig;
+
+use roperator::serde_json::{json, Value};
+
+const DEFAULT_ACCESS_MODE: &str = "ReadWriteOnce";
+
+// Binary suffixes from the largest, as the API server writes quantities back
+const UNITS: &[(&str, u64)] = &[("Pi", 1 << 30), ("Ti", 1 << 20), ("Gi", 1 << 10)];
+
+/// Storage size in MiB as a quantity, in the largest unit that holds it
+/// exactly. This is the form the API server stores, so that an unchanged
+/// size reads back the same.
+pub fn quantity(storage_size: u64) -> String {
+    UNITS
+        .iter()
+        .find(|(_, mib)| storage_size > 0 && storage_size % mib == 0)
+        .map(|(unit, mib)| format!("{}{}", storage_size / mib, unit))
+        .unwrap_or_else(|| format!("{}Mi", storage_size))
+}
+
+/// Volume claim template of a StatefulSet, with the class and access modes
+/// from the spec
+pub fn volume_claim_template(
+    name: &str,
+    storage_size: u64,
+    storage: Option<&StorageConfig>,
+) -> Value {
+    let mut template = json!({
+        "metadata": {
+            "name": name
+        },
+        "spec": {
+            "accessModes": [DEFAULT_ACCESS_MODE],
+            "resources": {
+                "requests": {
+                    "storage": quantity(storage_size)
+                }
+            }
+        }
+    });
+
+    if let Some(storage) = storage {
+        if let Some(class_name) = &storage.class_name {
+            template["spec"]["storageClassName"] = json!(class_name);
+        }
+        if let Some(access_modes) = &storage.access_modes {
+            template["spec"]["accessModes"] = json!(access_modes);
+        }
+    }
+
+    template
+}
+
+/// What happens to the volume claims once the StatefulSet is deleted, such
+/// as when its deployment leaves the spec. Left to Kubernetes, which retains
+/// them, unless the spec says otherwise.
+pub fn retention_policy(storage: Option<&StorageConfig>) -> Option<Value> {
+    match storage {
+        Some(StorageConfig {
+            retention_policy: Some(policy),
+            ..
+        }) => Some(json!({
+            "whenDeleted": policy,
+            "whenScaled": "Retain",
+        })),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantity_cases() {
+        let cases = vec![
+            (0, "0Mi"),
+            (512, "512Mi"),
+            (1024, "1Gi"),
+            (1536, "1536Mi"),
+            (2048, "2Gi"),
+            (1 << 20, "1Ti"),
+            (3 << 30, "3Pi"),
+        ];
+
+        for (storage_size, expected) in cases {
+            assert_eq!(quantity(storage_size), expected, "{}", storage_size);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use super::{IssuerConfig, TlsConfig};
+
+use roperator::serde_json::{json, Value};
+
+const DEFAULT_ISSUER_KIND: &str = "Issuer";
+
+/// Name of the Secret with `tls.crt`, `tls.key` and `ca.crt`, either the one
+/// referenced by the spec or the one cert-manager issues for the component
+pub fn secret_name(tls: &TlsConfig, component_name: &str) -> String {
+    match &tls.secret {
+        Some(secret) => secret.clone(),
+        None => format!("{}-tls", component_name),
+    }
+}
+
+/// cert-manager Certificate, when the spec asks for an issuer instead of a Secret
+pub fn certificate(
+    tls: &TlsConfig,
+    component_name: &str,
+    namespace: &str,
+    dns_names: Vec<String>,
+) -> Option<Value> {
+    match tls {
+        TlsConfig {
+            secret: None,
+            issuer: Some(IssuerConfig { name, kind }),
+        } => Some(json!({
+            "apiVersion": "cert-manager.io/v1",
+            "kind": "Certificate",
+            "metadata": {
+                "name": component_name,
+                "namespace": namespace,
+            },
+            "spec": {
+                "secretName": secret_name(tls, component_name),
+                "dnsNames": dns_names,
+                "issuerRef": {
+                    "name": name,
+                    "kind": kind.as_deref().unwrap_or(DEFAULT_ISSUER_KIND),
+                }
+            }
+        })),
+        _ => None,
+    }
+}
+
+/// Volume with the certificate Secret
+pub fn volume(secret: &str) -> Value {
+    json!({
+        "name": "tls",
+        "secret": {
+            "secretName": secret
+        }
+    })
+}
@@ -1,15 +1,91 @@
+use super::resources::{self, DEFAULT_MEMORY_MULTIPLIER};
+use super::scheduling;
 use super::Builder;
-use super::NoriaUiConfig;
-use super::{DEFAULT_NORIA_VERSION, NORIA_IMAGE};
+use super::{NoriaUiConfig, SchedulingConfig, UiAuthConfig, UiIngressConfig};
+use super::{CONFLUENT_ZOOKEEPER_IMAGE, DEFAULT_NORIA_VERSION, NORIA_IMAGE};
+use super::{NGINX_IMAGE, OAUTH2_PROXY_IMAGE};
+
+use super::zookeeper::DEFAULT_ZOOKEEPER_VERSION;
 
 use roperator::serde_json::{json, Value};
 
+// With authentication, the UI only listens on the loopback interface and a
+// proxy sidecar serves the Service port
+const UI_PORT: u16 = 8000;
+const AUTH_PROXY_PORT: u16 = 4180;
+
+// Each deployment's UI is served below <path>/<id>/, so that one Ingress
+// host can route to all of them
+const UI_ROOT: &str = "/srv/www";
+const DEFAULT_INGRESS_PATH: &str = "/";
+
+// nginx serves the UI and proxies its controller calls, whose target the
+// discovery sidecar writes to an include file and nginx reloads on change
+const UI_NGINX_PATH: &str = "/etc/nginx/conf.d";
+const CONTROLLER_INCLUDE: &str = "controller.inc";
+const RELOAD_INTERVAL_SECONDS: u64 = 5;
+
+const DISCOVERY_INTERVAL_SECONDS: u64 = 30;
+const DEFAULT_HTPASSWD_KEY: &str = "auth";
+const HTPASSWD_PATH: &str = "/etc/nginx/htpasswd";
+
 pub struct Config<'svc> {
-    name: &'svc str,
+    name: String,
+    noria_name: &'svc str,
     version: &'svc str,
+    resources: Value,
+    scheduling: Option<&'svc SchedulingConfig>,
+    deployment_ids: Vec<&'svc str>,
+    zookeeper_address: &'svc str,
+    ingress: Option<&'svc UiIngressConfig>,
+    auth: Option<&'svc UiAuthConfig>,
+    secret_checksums: Value,
+}
+
+/// Secret of the authentication proxy, if there is one
+pub fn auth_secret(noria_ui: &Option<NoriaUiConfig>) -> Option<&str> {
+    match noria_ui {
+        Some(NoriaUiConfig {
+            auth:
+                Some(UiAuthConfig {
+                    oauth2: Some(oauth2),
+                    ..
+                }),
+            ..
+        }) => Some(&oauth2.secret),
+        Some(NoriaUiConfig {
+            auth: Some(UiAuthConfig {
+                basic: Some(basic), ..
+            }),
+            ..
+        }) => Some(&basic.secret),
+        _ => None,
+    }
+}
+
+/// Name of the UI of one deployment
+pub fn name(noria_name: &str, deployment_id: &str) -> String {
+    format!("noria-ui-{}-{}", noria_name, deployment_id)
+}
+
+/// Whether the UIs are run, unless `enabled: false`
+pub fn enabled(noria_ui: &Option<NoriaUiConfig>) -> bool {
+    match noria_ui {
+        Some(NoriaUiConfig {
+            enabled: Some(enabled),
+            ..
+        }) => *enabled,
+        _ => true,
+    }
 }
 
-pub fn create_config<'svc>(noria_ui: &'svc Option<NoriaUiConfig>) -> Config<'svc> {
+pub fn create_config<'svc>(
+    noria_name: &'svc str,
+    noria_ui: &'svc Option<NoriaUiConfig>,
+    deployment_ids: Vec<&'svc str>,
+    zookeeper_address: &'svc str,
+    secret_checksums: Value,
+) -> Config<'svc> {
     let noria_ui_version = match noria_ui {
         Some(NoriaUiConfig {
             version: Some(v), ..
@@ -17,91 +93,519 @@ pub fn create_config<'svc>(noria_ui: &'svc Option<NoriaUiConfig>) -> Config<'svc
         _ => DEFAULT_NORIA_VERSION,
     };
 
+    let noria_ui_ingress = match noria_ui {
+        Some(NoriaUiConfig {
+            ingress: Some(i), ..
+        }) => Some(i),
+        _ => None,
+    };
+
+    let noria_ui_auth = match noria_ui {
+        Some(NoriaUiConfig { auth: Some(a), .. }) if a.oauth2.is_some() || a.basic.is_some() => {
+            Some(a)
+        }
+        _ => None,
+    };
+
+    let noria_ui_memory_multiplier = match noria_ui {
+        Some(NoriaUiConfig {
+            memory_multiplier: Some(m),
+            ..
+        }) => *m,
+        _ => DEFAULT_MEMORY_MULTIPLIER,
+    };
+
+    let noria_ui_resources = match noria_ui {
+        Some(NoriaUiConfig {
+            max_heap,
+            resources: r,
+            ..
+        }) => resources::container_resources(*max_heap, noria_ui_memory_multiplier, r, json!({})),
+        None => json!({}),
+    };
+
+    let noria_ui_scheduling = match noria_ui {
+        Some(NoriaUiConfig {
+            scheduling: Some(s),
+            ..
+        }) => Some(s),
+        _ => None,
+    };
+
     Config {
-        name: "noria-ui",
+        name: format!("noria-ui-{}", noria_name),
+        noria_name,
         version: noria_ui_version,
+        resources: noria_ui_resources,
+        scheduling: noria_ui_scheduling,
+        deployment_ids,
+        zookeeper_address,
+        ingress: noria_ui_ingress,
+        auth: noria_ui_auth,
+        secret_checksums,
+    }
+}
+
+impl<'svc> Config<'svc> {
+    // URL path of a deployment's UI
+    fn path(&self, deployment_id: &str) -> String {
+        let prefix = self
+            .ingress
+            .and_then(|ingress| ingress.path.as_deref())
+            .unwrap_or(DEFAULT_INGRESS_PATH)
+            .trim_end_matches('/');
+        format!("{}/{}", prefix, deployment_id)
+    }
+
+    // oauth2-proxy, or nginx checking an htpasswd file
+    fn auth_proxy(&self, auth: &UiAuthConfig) -> (Value, Vec<Value>) {
+        match auth {
+            UiAuthConfig {
+                oauth2: Some(oauth2),
+                ..
+            } => {
+                let mut args = vec![
+                    format!("--http-address=0.0.0.0:{}", AUTH_PROXY_PORT),
+                    format!("--upstream=http://127.0.0.1:{}/", UI_PORT),
+                    format!("--provider={}", oauth2.provider),
+                ];
+                match &oauth2.email_domains {
+                    Some(domains) => args.extend(
+                        domains
+                            .iter()
+                            .map(|domain| format!("--email-domain={}", domain)),
+                    ),
+                    None => args.push(String::from("--email-domain=*")),
+                }
+                if let Some(ingress) = self.ingress {
+                    let scheme = match ingress.tls_secret {
+                        Some(_) => "https",
+                        None => "http",
+                    };
+                    args.push(format!(
+                        "--redirect-url={}://{}/oauth2/callback",
+                        scheme, ingress.host
+                    ));
+                }
+                args.extend(oauth2.args.iter().flatten().cloned());
+
+                let secret_env = |name: &str, key: &str| {
+                    json!({
+                        "name": name,
+                        "valueFrom": {
+                            "secretKeyRef": {
+                                "name": oauth2.secret,
+                                "key": key,
+                            }
+                        }
+                    })
+                };
+
+                (
+                    json!({
+                        "name": "oauth2-proxy",
+                        "image": OAUTH2_PROXY_IMAGE,
+                        "args": args,
+                        "env": [
+                            secret_env("OAUTH2_PROXY_CLIENT_ID", "client-id"),
+                            secret_env("OAUTH2_PROXY_CLIENT_SECRET", "client-secret"),
+                            secret_env("OAUTH2_PROXY_COOKIE_SECRET", "cookie-secret"),
+                        ],
+                    }),
+                    vec![],
+                )
+            }
+            UiAuthConfig {
+                basic: Some(basic), ..
+            } => (
+                json!({
+                    "name": "basic-auth",
+                    "image": NGINX_IMAGE,
+                    "volumeMounts": [{
+                        "mountPath": "/etc/nginx/conf.d",
+                        "name": "nginx",
+                        "readOnly": true
+                    }, {
+                        "mountPath": HTPASSWD_PATH,
+                        "name": "htpasswd",
+                        "readOnly": true
+                    }],
+                }),
+                vec![
+                    json!({
+                        "name": "nginx",
+                        "configMap": {
+                            "name": format!("{}-nginx", self.name)
+                        }
+                    }),
+                    json!({
+                        "name": "htpasswd",
+                        "secret": {
+                            "secretName": basic.secret,
+                            "items": [{
+                                "key": basic.key.as_deref().unwrap_or(DEFAULT_HTPASSWD_KEY),
+                                "path": "htpasswd",
+                            }]
+                        }
+                    }),
+                ],
+            ),
+            _ => (Value::Null, vec![]),
+        }
     }
 }
 
 impl<'svc> Builder for Config<'svc> {
     fn children(self: &Config<'svc>, namespace: &str) -> Vec<Value> {
         let mut children = vec![];
-        // Noria-mysql Service
-        children.push(json!({
-            "apiVersion": "v1",
-            "kind": "Service",
-            "metadata": {
-                "name": self.name,
-                "namespace": namespace,
-            },
-            "spec": {
-                "ports": [{
-                    "port": 80,
-                    "name": "ui",
-                    "targetPort": 8000,
-                }],
-                "selector": {
-                    "noria-operator.io/kind": "noria-ui",
-                    "noria-operator.io/name": "noria"
+
+        // without a noria-server, there is no controller to follow
+        if self.deployment_ids.is_empty() {
+            return children;
+        }
+
+        let target_port = match self.auth {
+            Some(_) => AUTH_PROXY_PORT,
+            None => UI_PORT,
+        };
+
+        if let Some(UiAuthConfig { basic: Some(_), .. }) = self.auth {
+            children.push(json!({
+                "apiVersion": "v1",
+                "kind": "ConfigMap",
+                "metadata": {
+                    "name": format!("{}-nginx", self.name),
+                    "namespace": namespace,
+                },
+                "data": {
+                    "default.conf": format!(
+                        "server {{\n  listen {};\n  location / {{\n    auth_basic \"Noria\";\n    auth_basic_user_file {}/htpasswd;\n    proxy_pass http://127.0.0.1:{};\n  }}\n}}\n",
+                        AUTH_PROXY_PORT, HTPASSWD_PATH, UI_PORT
+                    )
                 }
-            }
-        }));
-
-        // Noria-mysql Deployment
-        children.push(json!({
-            "apiVersion": "extensions/v1beta1",
-            "kind": "Deployment",
-            "metadata": {
-                "name": self.name,
-                "namespace": namespace,
-            },
-            "spec": {
-                "replicas": 1,
-                "selector": {
-                    "matchLabels": {
+            }));
+        }
+
+        for id in &self.deployment_ids {
+            let name = name(self.noria_name, id);
+            let instance = format!("{}-{}", self.noria_name, id);
+            let ui_path = format!("{}{}", UI_ROOT, self.path(id));
+
+            // Noria-ui Service
+            children.push(json!({
+                "apiVersion": "v1",
+                "kind": "Service",
+                "metadata": {
+                    "name": name,
+                    "namespace": namespace,
+                },
+                "spec": {
+                    "ports": [{
+                        "port": 80,
+                        "name": "ui",
+                        "targetPort": target_port,
+                    }],
+                    "selector": {
                         "noria-operator.io/kind": "noria-ui",
-                        "noria-operator.io/name": "noria",
+                        "noria-operator.io/name": instance
+                    }
+                }
+            }));
+
+            // Browsers only talk to the UI's own origin, which forwards
+            // controller calls, so that they pass the authentication proxy
+            let controller_path = format!("{}/controller", self.path(id));
+            let listen = match self.auth {
+                Some(_) => format!("127.0.0.1:{}", UI_PORT),
+                None => UI_PORT.to_string(),
+            };
+            let init_command = format!(
+                r#"mkdir -p {ui} && cp -r /srv/noria-ui/. {ui}/
+                mkdir -p {ui}/js
+                echo 'var CONTROLLER = "{controller}";' > {ui}/js/config.js
+                echo 'return 503;' > {nginx}/{include}
+                {{
+                  echo 'server {{'
+                  echo '  listen {listen};'
+                  echo '  root {root};'
+                  echo '  location = /healthz {{ return 200; }}'
+                  echo '  location {controller}/ {{ include {nginx}/{include}; }}'
+                  echo '}}'
+                }} > {nginx}/default.conf"#,
+                ui = ui_path,
+                root = UI_ROOT,
+                controller = controller_path,
+                listen = listen,
+                nginx = UI_NGINX_PATH,
+                include = CONTROLLER_INCLUDE,
+            );
+            let nginx_command = format!(
+                r#"nginx -g 'daemon off;' &
+                nginx=$!
+                trap 'nginx -s quit' TERM
+                applied=$(cat {nginx}/{include})
+                while kill -0 "$nginx" 2> /dev/null; do
+                  sleep {}
+                  current=$(cat {nginx}/{include})
+                  if [ "$current" != "$applied" ]; then
+                    applied=$current
+                    nginx -s reload
+                  fi
+                done"#,
+                RELOAD_INTERVAL_SECONDS,
+                nginx = UI_NGINX_PATH,
+                include = CONTROLLER_INCLUDE,
+            );
+
+            // The controller moves between noria-server pods, so its address
+            // is looked up in the deployment's ZooKeeper path again and again
+            let discovery_command = format!(
+                r#"while true; do
+                  address=$(zookeeper-shell {} get /{}/controller 2>/dev/null \
+                    | sed -n 's/.*"external_addr":"\([^"]*\)".*/\1/p' | head -n1)
+                  if [ -n "$address" ]; then
+                    echo "proxy_pass http://$address/;" > {nginx}/{include}.tmp
+                    mv {nginx}/{include}.tmp {nginx}/{include}
+                  fi
+                  sleep {}
+                done"#,
+                self.zookeeper_address,
+                id,
+                DISCOVERY_INTERVAL_SECONDS,
+                nginx = UI_NGINX_PATH,
+                include = CONTROLLER_INCLUDE,
+            );
+
+            // Noria-ui Deployment
+            let mut deployment = json!({
+                "apiVersion": "apps/v1",
+                "kind": "Deployment",
+                "metadata": {
+                    "name": name,
+                    "namespace": namespace,
+                    "labels": {
+                        "noria-operator.io/kind": "noria-ui",
+                        "noria-operator.io/name": instance,
+                        "noria-operator.io/deployment": id
                     }
                 },
-                "strategy": {
-                    "type": "RollingUpdate"
-                },
-                "template": {
-                    "metadata": {
-                        "name": self.name,
-                        "labels": {
+                "spec": {
+                    "replicas": 1,
+                    "selector": {
+                        "matchLabels": {
                             "noria-operator.io/kind": "noria-ui",
-                            "noria-operator.io/name": "noria"
+                            "noria-operator.io/name": instance,
                         }
                     },
-                    "spec": {
-                        "containers": [{
-                            "command": ["python3", "-m", "http.server"],
-                            "image": format!("{}:{}", NORIA_IMAGE, self.version),
-                            "imagePullPolicy": "Always",
-                            "workingDir": "/srv/noria-ui",
-                            "name": "noria-ui",
-                            "ports": [{
-                                "containerPort": 8000,
-                                "name": "web",
-                                "protocol": "TCP",
+                    "strategy": {
+                        "type": "RollingUpdate"
+                    },
+                    "template": {
+                        "metadata": {
+                            "name": name,
+                            "labels": {
+                                "noria-operator.io/kind": "noria-ui",
+                                "noria-operator.io/name": instance
+                            }
+                        },
+                        "spec": {
+                            "initContainers": [{
+                                "name": "init-ui",
+                                "image": format!("{}:{}", NORIA_IMAGE, self.version),
+                                "command": ["bash", "-exc"],
+                                "args": [init_command],
+                                "volumeMounts": [{
+                                    "mountPath": UI_ROOT,
+                                    "name": "ui"
+                                }, {
+                                    "mountPath": UI_NGINX_PATH,
+                                    "name": "ui-nginx"
+                                }]
                             }],
-                            "livenessProbe": {
-                                "httpGet": {
-                                    "path": "/",
-                                    "port": 8000
+                            "containers": [{
+                                "command": ["sh", "-ec"],
+                                "args": [nginx_command],
+                                "image": NGINX_IMAGE,
+                                "name": "noria-ui",
+                                "resources": self.resources,
+                                "ports": [{
+                                    "containerPort": UI_PORT,
+                                    "name": "web",
+                                    "protocol": "TCP",
+                                }],
+                                "livenessProbe": {
+                                    "httpGet": {
+                                        "path": "/healthz",
+                                        "port": UI_PORT
+                                    },
+                                    "failureThreshold": 3,
+                                    "initialDelaySeconds": 30,
+                                    "periodSeconds": 10,
+                                    "successThreshold": 1,
+                                    "timeoutSeconds": 5,
                                 },
-                                "failureThreshold": 3,
-                                "initialDelaySeconds": 30,
-                                "periodSeconds": 10,
-                                "successThreshold": 1,
-                                "timeoutSeconds": 5,
-                            },
-                        }]
+                                "volumeMounts": [{
+                                    "mountPath": UI_ROOT,
+                                    "name": "ui",
+                                    "readOnly": true
+                                }, {
+                                    "mountPath": UI_NGINX_PATH,
+                                    "name": "ui-nginx",
+                                    "readOnly": true
+                                }]
+                            }, {
+                                "name": "controller-discovery",
+                                "image": format!("{}:{}", CONFLUENT_ZOOKEEPER_IMAGE, DEFAULT_ZOOKEEPER_VERSION),
+                                "command": ["bash", "-c"],
+                                "args": [discovery_command],
+                                "volumeMounts": [{
+                                    "mountPath": UI_NGINX_PATH,
+                                    "name": "ui-nginx"
+                                }]
+                            }],
+                            "volumes": [{
+                                "name": "ui",
+                                "emptyDir": {}
+                            }, {
+                                "name": "ui-nginx",
+                                "emptyDir": {}
+                            }]
+                        }
+                    }
+                }
+            });
+
+            // The UI is kept off the pod IP, so that only authenticated requests reach it
+            if let Some(auth) = self.auth {
+                let (mut proxy, volumes) = self.auth_proxy(auth);
+                proxy["ports"] = json!([{
+                    "containerPort": AUTH_PROXY_PORT,
+                    "name": "web",
+                    "protocol": "TCP",
+                }]);
+                proxy["livenessProbe"] = json!({
+                    "tcpSocket": {
+                        "port": AUTH_PROXY_PORT
+                    },
+                    "failureThreshold": 3,
+                    "initialDelaySeconds": 10,
+                    "periodSeconds": 10,
+                    "successThreshold": 1,
+                    "timeoutSeconds": 5,
+                });
+
+                let template = &mut deployment["spec"]["template"];
+                template["metadata"]["annotations"] = self.secret_checksums.clone();
+
+                let ui = &mut template["spec"]["containers"][0];
+                ui["ports"] = json!([]);
+                ui["livenessProbe"]["exec"] = json!({
+                    "command": [
+                        "wget",
+                        "-q",
+                        "-O",
+                        "/dev/null",
+                        format!("http://127.0.0.1:{}/healthz", UI_PORT)
+                    ]
+                });
+                if let Some(probe) = ui["livenessProbe"].as_object_mut() {
+                    probe.remove("httpGet");
+                }
+
+                if let Some(containers) = template["spec"]["containers"].as_array_mut() {
+                    containers.push(proxy);
+                }
+                if let Some(pod_volumes) = template["spec"]["volumes"].as_array_mut() {
+                    pod_volumes.extend(volumes);
+                }
+            }
+
+            scheduling::apply(
+                &mut deployment["spec"]["template"]["spec"],
+                self.scheduling,
+                None,
+            );
+
+            children.push(deployment);
+        }
+
+        // Noria-ui Ingress, routing each deployment's path to its UI
+        if let Some(ingress) = self.ingress {
+            let backend = |id: &str| {
+                json!({
+                    "service": {
+                        "name": name(self.noria_name, id),
+                        "port": {
+                            "name": "ui"
+                        }
+                    }
+                })
+            };
+
+            let mut paths = self
+                .deployment_ids
+                .iter()
+                .map(|id| {
+                    json!({
+                        "path": self.path(id),
+                        "pathType": "Prefix",
+                        "backend": backend(id),
+                    })
+                })
+                .collect::<Vec<Value>>();
+
+            // oauth2-proxy handles its callback at the root of the host, any
+            // deployment's proxy can complete it
+            if let (
+                Some(UiAuthConfig {
+                    oauth2: Some(_), ..
+                }),
+                Some(id),
+            ) = (self.auth, self.deployment_ids.first())
+            {
+                paths.push(json!({
+                    "path": "/oauth2",
+                    "pathType": "Prefix",
+                    "backend": backend(id),
+                }));
+            }
+
+            let mut ingress_child = json!({
+                "apiVersion": "networking.k8s.io/v1",
+                "kind": "Ingress",
+                "metadata": {
+                    "name": self.name,
+                    "namespace": namespace,
+                    "labels": {
+                        "noria-operator.io/kind": "noria-ui",
+                        "noria-operator.io/name": self.noria_name
                     }
+                },
+                "spec": {
+                    "rules": [{
+                        "host": ingress.host,
+                        "http": {
+                            "paths": paths
+                        }
+                    }]
                 }
+            });
+
+            if let Some(annotations) = &ingress.annotations {
+                ingress_child["metadata"]["annotations"] = json!(annotations);
+            }
+            if let Some(class_name) = &ingress.class_name {
+                ingress_child["spec"]["ingressClassName"] = json!(class_name);
             }
-        }));
+            if let Some(tls_secret) = &ingress.tls_secret {
+                ingress_child["spec"]["tls"] = json!([{
+                    "hosts": [ingress.host],
+                    "secretName": tls_secret,
+                }]);
+            }
+
+            children.push(ingress_child);
+        }
 
         children
     }
@@ -0,0 +1,622 @@
+use super::resources::parse_quantity;
+use super::KUBECTL_IMAGE;
+
+use k8s_types::apps::v1 as apps;
+
+use roperator::prelude::*;
+use roperator::serde_json::{json, Value};
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use log::*;
+
+const UPDATE_ACTION_ANNOTATION: &str = "noria-operator.io/update-action";
+
+// Fields of a StatefulSet spec that the API server refuses to update
+const STATEFULSET_IMMUTABLE_FIELDS: &[&str] = &[
+    "/spec/selector",
+    "/spec/serviceName",
+    "/spec/podManagementPolicy",
+    "/spec/volumeClaimTemplates",
+];
+
+// How an existing StatefulSet is brought in line with its desired state
+enum Action {
+    InPlace,
+    // delete the StatefulSet but keep its pods running, the new one adopts them
+    OrphanRecreate,
+    // the selector changed and pods cannot be adopted, delete them as well
+    Recreate,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::InPlace => "in-place",
+            Action::OrphanRecreate => "orphan-recreate",
+            Action::Recreate => "recreate",
+        }
+    }
+}
+
+fn calculate_hash<T: Hash>(t: &T) -> String {
+    let mut s = DefaultHasher::new();
+    t.hash(&mut s);
+    let b = s.finish().to_be_bytes();
+    hex::encode(&b[..4])
+}
+
+// Whether every field set in desired has the same value in observed. The API
+// server adds defaults and status, so the objects are never equal.
+fn is_subset(desired: &Value, observed: &Value) -> bool {
+    match (desired, observed) {
+        (Value::Object(desired), Value::Object(observed)) => desired
+            .iter()
+            .all(|(key, value)| observed.get(key).map_or(false, |o| is_subset(value, o))),
+        (Value::Array(desired), Value::Array(observed)) => {
+            desired.len() == observed.len()
+                && desired.iter().zip(observed).all(|(d, o)| is_subset(d, o))
+        }
+        (desired, observed) => desired == observed,
+    }
+}
+
+fn statefulset_action(desired: &Value, observed: &Value) -> Action {
+    let changed = |path: &str| match desired.pointer(path) {
+        Some(field) => !observed
+            .pointer(path)
+            .map_or(false, |o| is_subset(field, o)),
+        None => false,
+    };
+
+    if changed("/spec/selector") {
+        Action::Recreate
+    } else if STATEFULSET_IMMUTABLE_FIELDS
+        .iter()
+        .any(|path| changed(path))
+    {
+        Action::OrphanRecreate
+    } else {
+        Action::InPlace
+    }
+}
+
+fn template_storage(template: &Value) -> Option<&str> {
+    template
+        .pointer("/spec/resources/requests/storage")
+        .and_then(Value::as_str)
+}
+
+// Claims of the observed pods whose template asks for more storage than
+// before, with their new size. The templates of a StatefulSet only apply to
+// new claims, so existing ones are expanded one by one.
+fn expanded_claims(desired: &Value, observed: &Value) -> Vec<(String, String)> {
+    let name = desired["metadata"]["name"].as_str().unwrap_or_default();
+    let replicas = observed
+        .pointer("/spec/replicas")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    let observed_templates = observed
+        .pointer("/spec/volumeClaimTemplates")
+        .and_then(Value::as_array);
+
+    let mut claims = vec![];
+    for template in desired
+        .pointer("/spec/volumeClaimTemplates")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let template_name = template["metadata"]["name"].as_str().unwrap_or_default();
+        let size = match template_storage(template) {
+            Some(size) => size,
+            None => continue,
+        };
+        let observed_size = observed_templates
+            .into_iter()
+            .flatten()
+            .find(|o| o["metadata"]["name"] == template_name)
+            .and_then(template_storage);
+
+        match (parse_quantity(size), observed_size.and_then(parse_quantity)) {
+            (Some(desired_bytes), Some(observed_bytes)) if desired_bytes > observed_bytes => {
+                for n in 0..replicas {
+                    claims.push((
+                        format!("{}-{}-{}", template_name, name, n),
+                        size.to_string(),
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    claims
+}
+
+// Claims of the observed pods that Kubernetes deletes along with the
+// StatefulSet, because of a `Delete` retention policy
+fn owned_claims(observed: &Value) -> Vec<String> {
+    if observed.pointer("/spec/persistentVolumeClaimRetentionPolicy/whenDeleted")
+        != Some(&json!("Delete"))
+    {
+        return vec![];
+    }
+
+    let name = observed["metadata"]["name"].as_str().unwrap_or_default();
+    let replicas = observed
+        .pointer("/spec/replicas")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+
+    let mut claims = vec![];
+    for template in observed
+        .pointer("/spec/volumeClaimTemplates")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let template_name = template["metadata"]["name"].as_str().unwrap_or_default();
+        for n in 0..replicas {
+            claims.push(format!("{}-{}-{}", template_name, name, n));
+        }
+    }
+
+    claims
+}
+
+// Job deleting a StatefulSet, after which the operator creates it again from
+// the desired state. PersistentVolumeClaims are never deleted with it: a
+// `Delete` retention policy is switched to `Retain` first, and the Job waits
+// for Kubernetes to release the owned claims. Grown claims are expanded
+// first, which needs a StorageClass that allows it.
+fn recreate_job(
+    statefulset: &Value,
+    action: &Action,
+    claims: &[(String, String)],
+    owned_claims: &[String],
+    service_account: &str,
+) -> Value {
+    let name = statefulset["metadata"]["name"].as_str().unwrap_or_default();
+    let namespace = statefulset["metadata"]["namespace"]
+        .as_str()
+        .unwrap_or_default();
+    let cascade = match action {
+        Action::OrphanRecreate => "orphan",
+        _ => "foreground",
+    };
+
+    // a claim of a pod that never started does not exist
+    let mut script = claims.iter().fold(String::new(), |mut acc, (claim, size)| {
+        acc.push_str(&format!(
+            r#"if kubectl get pvc {claim} --namespace {namespace} > /dev/null; then
+                  kubectl patch pvc {claim} --namespace {namespace} \
+                    -p '{{"spec":{{"resources":{{"requests":{{"storage":"{size}"}}}}}}}}'
+                fi
+                "#,
+            claim = claim,
+            namespace = namespace,
+            size = size,
+        ));
+        acc
+    });
+    if !owned_claims.is_empty() {
+        script.push_str(&format!(
+            r#"kubectl patch statefulset {name} --namespace {namespace} \
+                  -p '{{"spec":{{"persistentVolumeClaimRetentionPolicy":{{"whenDeleted":"Retain"}}}}}}'
+                for claim in {claims}; do
+                  while kubectl get pvc $claim --namespace {namespace} \
+                    -o jsonpath='{{.metadata.ownerReferences[?(@.kind=="StatefulSet")].name}}' \
+                    | grep -q .; do
+                    sleep 1
+                  done
+                done
+                "#,
+            name = name,
+            namespace = namespace,
+            claims = owned_claims.join(" "),
+        ));
+    }
+    script.push_str(&format!(
+        "kubectl delete statefulset {} --namespace {} --cascade={} --wait=true",
+        name, namespace, cascade
+    ));
+
+    json!({
+        "apiVersion": "batch/v1",
+        "kind": "Job",
+        "metadata": {
+            "name": format!("{}-recreate-{}", name, calculate_hash(&statefulset["spec"].to_string())),
+            "namespace": namespace,
+        },
+        "spec": {
+            "backoffLimit": 3,
+            "template": {
+                "spec": {
+                    "serviceAccountName": service_account,
+                    "restartPolicy": "OnFailure",
+                    "containers": [{
+                        "name": "kubectl",
+                        "image": KUBECTL_IMAGE,
+                        "command": ["bash", "-ec"],
+                        "args": [script],
+                    }]
+                }
+            }
+        }
+    })
+}
+
+// Permissions of the recreate Jobs, removed again once no Job needs them
+fn recreate_rbac(service_account: &str, namespace: &str) -> Vec<Value> {
+    vec![
+        json!({
+            "apiVersion": "v1",
+            "kind": "ServiceAccount",
+            "metadata": {
+                "name": service_account,
+                "namespace": namespace,
+            }
+        }),
+        json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "Role",
+            "metadata": {
+                "name": service_account,
+                "namespace": namespace,
+            },
+            "rules": [{
+                "apiGroups": ["apps"],
+                "resources": ["statefulsets"],
+                "verbs": ["get", "patch", "delete"],
+            }, {
+                "apiGroups": [""],
+                "resources": ["persistentvolumeclaims"],
+                "verbs": ["get", "patch"],
+            }]
+        }),
+        json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "RoleBinding",
+            "metadata": {
+                "name": service_account,
+                "namespace": namespace,
+            },
+            "roleRef": {
+                "apiGroup": "rbac.authorization.k8s.io",
+                "kind": "Role",
+                "name": service_account,
+            },
+            "subjects": [{
+                "kind": "ServiceAccount",
+                "name": service_account,
+                "namespace": namespace,
+            }]
+        }),
+    ]
+}
+
+/// Decides how each desired StatefulSet is updated. Changes to mutable fields
+/// are applied in place, changes to immutable fields keep the existing
+/// StatefulSet untouched until a Job has deleted it.
+pub fn plan(request: &SyncRequest, noria_name: &str, namespace: &str, children: &mut Vec<Value>) {
+    plan_statefulsets(
+        |name| {
+            request
+                .children()
+                .of_type(apps::StatefulSet)
+                .get(namespace, name)
+        },
+        noria_name,
+        namespace,
+        children,
+    );
+}
+
+// Plans the updates against the StatefulSets `observe` finds by name
+fn plan_statefulsets<'a, F>(
+    observe: F,
+    noria_name: &str,
+    namespace: &str,
+    children: &mut Vec<Value>,
+) where
+    F: Fn(&str) -> Option<&'a Value>,
+{
+    let service_account = format!("noria-recreate-{}", noria_name);
+    let mut jobs = vec![];
+
+    for child in children.iter_mut() {
+        if child["kind"] != "StatefulSet" {
+            continue;
+        }
+
+        let name = child["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let observed = match observe(&name) {
+            Some(observed) => observed,
+            None => continue,
+        };
+
+        let action = statefulset_action(child, observed);
+        if let Action::InPlace = action {
+            child["metadata"]["annotations"][UPDATE_ACTION_ANNOTATION] = json!(action.as_str());
+            continue;
+        }
+
+        info!(
+            "StatefulSet {}/{} changed immutable fields, updating with {}",
+            namespace,
+            name,
+            action.as_str()
+        );
+
+        let claims = expanded_claims(child, observed);
+        let owned_claims = owned_claims(observed);
+        jobs.push(recreate_job(
+            child,
+            &action,
+            &claims,
+            &owned_claims,
+            &service_account,
+        ));
+
+        // keep what is running until the Job has deleted it, without undoing
+        // the retention policy the Job switches to
+        if let Some(spec) = observed.pointer("/spec") {
+            child["spec"] = spec.clone();
+        }
+        if !owned_claims.is_empty() {
+            child["spec"]["persistentVolumeClaimRetentionPolicy"]["whenDeleted"] = json!("Retain");
+        }
+        child["metadata"]["annotations"][UPDATE_ACTION_ANNOTATION] = json!(action.as_str());
+    }
+
+    if !jobs.is_empty() {
+        children.append(&mut recreate_rbac(&service_account, namespace));
+        children.append(&mut jobs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statefulset(selector: &str, storage: &str) -> Value {
+        json!({
+            "apiVersion": "apps/v1",
+            "kind": "StatefulSet",
+            "metadata": {
+                "name": "noria-server-test-xx",
+                "namespace": "default",
+            },
+            "spec": {
+                "replicas": 3,
+                "serviceName": "noria-server-test-xx-nodes",
+                "podManagementPolicy": "Parallel",
+                "selector": {
+                    "matchLabels": { "noria-operator.io/name": selector }
+                },
+                "template": {
+                    "spec": {
+                        "containers": [{ "name": "noria-server", "image": "noria:0.4.1" }]
+                    }
+                },
+                "volumeClaimTemplates": [{
+                    "metadata": { "name": "data" },
+                    "spec": {
+                        "accessModes": ["ReadWriteOnce"],
+                        "resources": { "requests": { "storage": storage } }
+                    }
+                }]
+            }
+        })
+    }
+
+    // What the API server returns for the desired StatefulSet, with defaults
+    // and status added
+    fn observed(desired: &Value) -> Value {
+        let mut observed = desired.clone();
+        observed["metadata"]["generation"] = json!(1);
+        observed["spec"]["revisionHistoryLimit"] = json!(10);
+        observed["spec"]["template"]["spec"]["containers"][0]["imagePullPolicy"] =
+            json!("IfNotPresent");
+        observed["spec"]["template"]["spec"]["restartPolicy"] = json!("Always");
+        observed["spec"]["volumeClaimTemplates"][0]["spec"]["volumeMode"] = json!("Filesystem");
+        observed["spec"]["volumeClaimTemplates"][0]["status"] = json!({ "phase": "Pending" });
+        observed["status"] = json!({ "replicas": 3, "readyReplicas": 3 });
+        observed
+    }
+
+    #[test]
+    fn is_subset_cases() {
+        let cases = vec![
+            (json!(1), json!(1), true),
+            (json!("a"), json!("b"), false),
+            (json!({}), json!({ "a": 1 }), true),
+            (json!({ "a": 1 }), json!({ "a": 1, "b": 2 }), true),
+            (json!({ "a": 1 }), json!({ "b": 2 }), false),
+            (
+                json!({ "a": { "b": 1 } }),
+                json!({ "a": { "b": 1, "c": 2 } }),
+                true,
+            ),
+            (
+                json!({ "a": { "b": 1 } }),
+                json!({ "a": { "b": 2 } }),
+                false,
+            ),
+            (json!([{ "a": 1 }]), json!([{ "a": 1, "b": 2 }]), true),
+            (json!([1, 2]), json!([1, 2, 3]), false),
+            (json!([1, 2]), json!([2, 1]), false),
+            (json!(null), json!({}), false),
+        ];
+
+        for (desired, observed, expected) in cases {
+            assert_eq!(
+                is_subset(&desired, &observed),
+                expected,
+                "is_subset({}, {})",
+                desired,
+                observed
+            );
+        }
+    }
+
+    #[test]
+    fn statefulset_action_cases() {
+        let current = statefulset("xx", "1024Mi");
+        let mut service_name = current.clone();
+        service_name["spec"]["serviceName"] = json!("other");
+        let mut image = current.clone();
+        image["spec"]["template"]["spec"]["containers"][0]["image"] = json!("noria:0.5.0");
+
+        let cases = vec![
+            (
+                "defaults added by the API server",
+                current.clone(),
+                "in-place",
+            ),
+            ("mutable field changed", image, "in-place"),
+            ("selector changed", statefulset("yy", "1024Mi"), "recreate"),
+            (
+                "claim templates changed",
+                statefulset("xx", "2048Mi"),
+                "orphan-recreate",
+            ),
+            ("service name changed", service_name, "orphan-recreate"),
+        ];
+
+        for (case, desired, expected) in cases {
+            assert_eq!(
+                statefulset_action(&desired, &observed(&current)).as_str(),
+                expected,
+                "{}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn plan_leaves_new_statefulsets_alone() {
+        let desired = statefulset("xx", "1024Mi");
+        let mut children = vec![desired.clone()];
+
+        plan_statefulsets(|_| None, "test", "default", &mut children);
+
+        assert_eq!(children, vec![desired]);
+    }
+
+    #[test]
+    fn plan_updates_in_place() {
+        let current = observed(&statefulset("xx", "1024Mi"));
+        let mut desired = statefulset("xx", "1024Mi");
+        desired["spec"]["replicas"] = json!(5);
+        let mut children = vec![desired];
+
+        plan_statefulsets(|_| Some(&current), "test", "default", &mut children);
+
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0]["spec"]["replicas"], 5);
+        assert_eq!(
+            children[0]["metadata"]["annotations"][UPDATE_ACTION_ANNOTATION],
+            "in-place"
+        );
+    }
+
+    #[test]
+    fn plan_recreates_with_a_job() {
+        let current = observed(&statefulset("xx", "1024Mi"));
+        let mut children = vec![statefulset("xx", "2048Mi")];
+
+        plan_statefulsets(|_| Some(&current), "test", "default", &mut children);
+
+        // the running StatefulSet is kept until the Job deleted it
+        assert_eq!(children[0]["spec"], current["spec"]);
+        assert_eq!(
+            children[0]["metadata"]["annotations"][UPDATE_ACTION_ANNOTATION],
+            "orphan-recreate"
+        );
+
+        let kinds: Vec<_> = children.iter().map(|child| &child["kind"]).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "StatefulSet",
+                "ServiceAccount",
+                "Role",
+                "RoleBinding",
+                "Job"
+            ]
+        );
+
+        let script = children[4]["spec"]["template"]["spec"]["containers"][0]["args"][0]
+            .as_str()
+            .unwrap();
+        assert!(script.contains("--cascade=orphan"));
+        assert!(script.contains("kubectl patch pvc data-noria-server-test-xx-0"));
+        assert!(!script.contains("persistentVolumeClaimRetentionPolicy"));
+    }
+
+    #[test]
+    fn plan_recreates_without_deleting_claims() {
+        let mut current = observed(&statefulset("xx", "1024Mi"));
+        current["spec"]["persistentVolumeClaimRetentionPolicy"] =
+            json!({ "whenDeleted": "Delete", "whenScaled": "Retain" });
+        let mut children = vec![statefulset("yy", "1024Mi")];
+
+        plan_statefulsets(|_| Some(&current), "test", "default", &mut children);
+
+        // the operator must not switch the policy back before the deletion
+        assert_eq!(
+            children[0]["spec"]["persistentVolumeClaimRetentionPolicy"]["whenDeleted"],
+            "Retain"
+        );
+
+        let script = children[4]["spec"]["template"]["spec"]["containers"][0]["args"][0]
+            .as_str()
+            .unwrap();
+        let retain = script.find(r#""whenDeleted":"Retain""#).unwrap();
+        let delete = script.find("--cascade=foreground").unwrap();
+        assert!(retain < delete);
+        assert!(script.contains(
+            "for claim in data-noria-server-test-xx-0 data-noria-server-test-xx-1 data-noria-server-test-xx-2;"
+        ));
+    }
+
+    #[test]
+    fn expanded_claims_cases() {
+        let current = observed(&statefulset("xx", "1024Mi"));
+
+        let cases = vec![
+            ("unchanged", "1024Mi", vec![]),
+            ("same size in another unit", "1Gi", vec![]),
+            ("shrunk", "512Mi", vec![]),
+            ("unparsable", "lots", vec![]),
+            (
+                "grown",
+                "2Gi",
+                vec![
+                    "data-noria-server-test-xx-0",
+                    "data-noria-server-test-xx-1",
+                    "data-noria-server-test-xx-2",
+                ],
+            ),
+        ];
+
+        for (case, size, expected) in cases {
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|claim| (claim.to_string(), size.to_string()))
+                .collect();
+            assert_eq!(
+                expanded_claims(&statefulset("xx", size), &current),
+                expected,
+                "{}",
+                case
+            );
+        }
+    }
+}
@@ -0,0 +1,644 @@
+use super::model::*;
+use super::mysql;
+use super::recipe;
+use super::resources::parse_quantity;
+use super::server::{self, DEFAULT_NORIA_SERVER_STORAGE_SIZE};
+use super::ui;
+use super::zookeeper::{self, DEFAULT_ZOOKEEPER_REPLICAS, DEFAULT_ZOOKEEPER_STORAGE_SIZE};
+
+use roperator::serde_json::Value;
+
+use std::collections::HashSet;
+
+// Storage sizes are given in MiB
+const MIN_STORAGE_SIZE: u64 = 1024;
+
+// Child names are DNS labels, Services directly and StatefulSets, CronJobs and
+// Jobs through the labels of their pods
+const MAX_NAME_LENGTH: usize = 63;
+// `-recreate-<hash>` of the Job replacing a StatefulSet, longer than the
+// revision hash in the labels of its pods and `-backup-<time>` of a CronJob
+const STATEFULSET_SUFFIX_LENGTH: usize = 18;
+// `-metrics` of the Services scraped by Prometheus
+const SERVICE_SUFFIX_LENGTH: usize = 8;
+// `-<hash>` of the recipe Jobs
+const RECIPE_SUFFIX_LENGTH: usize = 17;
+
+// Image tags, as accepted by docker
+fn is_valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version.len() <= 128
+        && !version.starts_with('.')
+        && !version.starts_with('-')
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+// Deployment ids end up in child names, which must be RFC 1123 DNS labels
+fn is_dns_label(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 63
+        && !id.starts_with('-')
+        && !id.ends_with('-')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+fn validate_name_length(errors: &mut Vec<String>, field: &str, name: &str, suffix_length: usize) {
+    if name.len() + suffix_length > MAX_NAME_LENGTH {
+        errors.push(format!(
+            "{}: makes the child name {} too long, it has {} characters and at most {} are left",
+            field,
+            name,
+            name.len(),
+            MAX_NAME_LENGTH - suffix_length
+        ));
+    }
+}
+
+fn validate_version(errors: &mut Vec<String>, field: &str, version: &Option<String>) {
+    if let Some(version) = version {
+        if !is_valid_version(version) {
+            errors.push(format!("{}: invalid version {:?}", field, version));
+        }
+    }
+}
+
+fn validate_storage_size(errors: &mut Vec<String>, field: &str, storage_size: Option<u64>) {
+    if let Some(storage_size) = storage_size {
+        if storage_size < MIN_STORAGE_SIZE {
+            errors.push(format!(
+                "{}: must be at least {} MiB ({})",
+                field, MIN_STORAGE_SIZE, storage_size
+            ));
+        }
+    }
+}
+
+// A certificate comes either from a Secret or from a cert-manager issuer
+fn validate_tls(errors: &mut Vec<String>, field: &str, tls: &Option<TlsConfig>) {
+    if let Some(tls) = tls {
+        if tls.secret.is_some() == tls.issuer.is_some() {
+            errors.push(format!("{}: set exactly one of secret and issuer", field));
+        }
+    }
+}
+
+// A number of pods, or a percentage such as "50%"
+fn is_pod_count(count: &Value) -> bool {
+    match count {
+        Value::Number(number) => number.as_u64().is_some(),
+        Value::String(percentage) => match percentage.strip_suffix('%') {
+            Some(digits) => !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+// A PodDisruptionBudget takes only one of the two
+fn validate_disruption_budget(
+    errors: &mut Vec<String>,
+    field: &str,
+    disruption_budget: &Option<DisruptionBudgetConfig>,
+) {
+    if let Some(disruption_budget) = disruption_budget {
+        if disruption_budget.min_available.is_some() && disruption_budget.max_unavailable.is_some()
+        {
+            errors.push(format!(
+                "{}: set at most one of min_available and max_unavailable",
+                field
+            ));
+        }
+        for (name, count) in &[
+            ("min_available", &disruption_budget.min_available),
+            ("max_unavailable", &disruption_budget.max_unavailable),
+        ] {
+            if let Some(count) = count {
+                if !is_pod_count(count) {
+                    errors.push(format!(
+                        "{}.{}: must be a number of pods or a percentage ({})",
+                        field, name, count
+                    ));
+                }
+            }
+        }
+    }
+}
+
+// Kubernetes rejects pods with quantities outside its grammar, and the heap
+// is sized from the memory limit
+fn validate_resources(errors: &mut Vec<String>, field: &str, resources: &Option<ResourcesConfig>) {
+    if let Some(resources) = resources {
+        for (kind, list) in &[
+            ("requests", &resources.requests),
+            ("limits", &resources.limits),
+        ] {
+            let mut quantities = list.iter().flatten().collect::<Vec<(&String, &Value)>>();
+            quantities.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, quantity) in quantities {
+                let valid = match quantity {
+                    Value::String(quantity) => parse_quantity(quantity).is_some(),
+                    Value::Number(number) => number.as_f64().map_or(false, |n| n >= 0.0),
+                    _ => false,
+                };
+                if !valid {
+                    errors.push(format!(
+                        "{}.{}.{}: invalid quantity {}",
+                        field, kind, name, quantity
+                    ));
+                }
+            }
+        }
+    }
+}
+
+// Fields that Kubernetes only accepts for some Service types
+fn validate_mysql_service(errors: &mut Vec<String>, field: &str, service: &MysqlServiceConfig) {
+    let service_type = service.service_type.as_deref().unwrap_or("ClusterIP");
+
+    if service.node_port.is_some() && service_type == "ClusterIP" {
+        errors.push(format!(
+            "{}.node_port: needs type NodePort or LoadBalancer",
+            field
+        ));
+    }
+    if service.external_traffic_policy.is_some() && service_type == "ClusterIP" {
+        errors.push(format!(
+            "{}.external_traffic_policy: needs type NodePort or LoadBalancer",
+            field
+        ));
+    }
+    if service.load_balancer_source_ranges.is_some() && service_type != "LoadBalancer" {
+        errors.push(format!(
+            "{}.load_balancer_source_ranges: needs type LoadBalancer",
+            field
+        ));
+    }
+}
+
+fn zookeeper_storage_size(spec: &NoriaSpec) -> u64 {
+    match &spec.zookeeper {
+        Some(ZookeeperConfig {
+            storage_size: Some(m),
+            ..
+        }) => *m,
+        _ => DEFAULT_ZOOKEEPER_STORAGE_SIZE,
+    }
+}
+
+fn server_storage_size(deployment: &Deployment) -> u64 {
+    match &deployment.noria_server {
+        Some(NoriaServerConfig {
+            storage_size: Some(m),
+            ..
+        }) => *m,
+        _ => DEFAULT_NORIA_SERVER_STORAGE_SIZE,
+    }
+}
+
+/// Problems with a Noria spec, empty if it can be applied
+pub fn validate(noria: &Noria) -> Vec<String> {
+    let mut errors = vec![];
+    let spec = &noria.spec;
+
+    let mut ids = HashSet::new();
+    for (i, deployment) in spec.deployments.iter().enumerate() {
+        let field = format!("spec.deployments[{}]", i);
+
+        if !ids.insert(deployment.id.as_str()) {
+            errors.push(format!("{}.id: duplicate id {:?}", field, deployment.id));
+        }
+        if !is_dns_label(&deployment.id) {
+            errors.push(format!(
+                "{}.id: must be a lowercase DNS label ({:?})",
+                field, deployment.id
+            ));
+        } else if deployment.id.contains('-') {
+            errors.push(format!(
+                "{}.id: must not contain dashes ({:?})",
+                field, deployment.id
+            ));
+        }
+
+        if server::enabled(&deployment.noria_server) {
+            validate_name_length(
+                &mut errors,
+                &format!("{}.id", field),
+                &server::name(&noria.metadata.name, &deployment.id),
+                STATEFULSET_SUFFIX_LENGTH,
+            );
+            if ui::enabled(&spec.noria_ui) {
+                validate_name_length(
+                    &mut errors,
+                    &format!("{}.id", field),
+                    &ui::name(&noria.metadata.name, &deployment.id),
+                    0,
+                );
+            }
+        }
+        if mysql::enabled(&deployment.noria_mysql) {
+            validate_name_length(
+                &mut errors,
+                &format!("{}.id", field),
+                &mysql::name(&noria.metadata.name, &deployment.id),
+                SERVICE_SUFFIX_LENGTH,
+            );
+            if deployment.recipe.is_some() {
+                validate_name_length(
+                    &mut errors,
+                    &format!("{}.id", field),
+                    &recipe::name(&noria.metadata.name, &deployment.id),
+                    RECIPE_SUFFIX_LENGTH,
+                );
+            }
+        }
+
+        if deployment.recipe.is_some() && !mysql::enabled(&deployment.noria_mysql) {
+            errors.push(format!(
+                "{}.recipe: is applied through noria_mysql, which is disabled",
+                field
+            ));
+        }
+
+        if deployment.restore.is_some() && !server::enabled(&deployment.noria_server) {
+            errors.push(format!(
+                "{}.restore: restores the volumes of noria_server, which is disabled",
+                field
+            ));
+        }
+
+        if let Some(server) = &deployment.noria_server {
+            validate_version(
+                &mut errors,
+                &format!("{}.noria_server.version", field),
+                &server.version,
+            );
+            validate_storage_size(
+                &mut errors,
+                &format!("{}.noria_server.storage_size", field),
+                server.storage_size,
+            );
+            validate_disruption_budget(
+                &mut errors,
+                &format!("{}.noria_server.disruption_budget", field),
+                &server.disruption_budget,
+            );
+            validate_resources(
+                &mut errors,
+                &format!("{}.noria_server.resources", field),
+                &server.resources,
+            );
+        }
+
+        if let Some(mysql) = &deployment.noria_mysql {
+            validate_version(
+                &mut errors,
+                &format!("{}.noria_mysql.version", field),
+                &mysql.version,
+            );
+            validate_tls(
+                &mut errors,
+                &format!("{}.noria_mysql.tls", field),
+                &mysql.tls,
+            );
+            validate_disruption_budget(
+                &mut errors,
+                &format!("{}.noria_mysql.disruption_budget", field),
+                &mysql.disruption_budget,
+            );
+            validate_resources(
+                &mut errors,
+                &format!("{}.noria_mysql.resources", field),
+                &mysql.resources,
+            );
+            if let Some(autoscaling) = &mysql.autoscaling {
+                if mysql.replicas.is_some() {
+                    errors.push(format!(
+                        "{}.noria_mysql.replicas: cannot be set together with autoscaling",
+                        field
+                    ));
+                }
+                if let Some(min_replicas) = autoscaling.min_replicas {
+                    if min_replicas > autoscaling.max_replicas {
+                        errors.push(format!(
+                            "{}.noria_mysql.autoscaling.min_replicas: must not exceed max_replicas ({} > {})",
+                            field, min_replicas, autoscaling.max_replicas
+                        ));
+                    }
+                }
+            }
+            if let Some(service) = &mysql.service {
+                validate_mysql_service(
+                    &mut errors,
+                    &format!("{}.noria_mysql.service", field),
+                    service,
+                );
+            }
+            if mysql.tls.is_some() && mysql.credentials.is_none() {
+                errors.push(format!(
+                    "{}.noria_mysql.tls: needs credentials to authenticate clients",
+                    field
+                ));
+            }
+        }
+    }
+
+    if zookeeper::external_config(&spec.zookeeper).is_none() {
+        validate_name_length(
+            &mut errors,
+            "metadata.name",
+            &zookeeper::name(&noria.metadata.name),
+            STATEFULSET_SUFFIX_LENGTH,
+        );
+    }
+
+    if let Some(zookeeper) = &spec.zookeeper {
+        let replicas = zookeeper.replicas.unwrap_or(DEFAULT_ZOOKEEPER_REPLICAS);
+        if replicas % 2 == 0 {
+            errors.push(format!(
+                "spec.zookeeper.replicas: must be an odd number greater than zero ({})",
+                replicas
+            ));
+        }
+        validate_version(&mut errors, "spec.zookeeper.version", &zookeeper.version);
+        validate_storage_size(
+            &mut errors,
+            "spec.zookeeper.storage_size",
+            zookeeper.storage_size,
+        );
+        validate_resources(
+            &mut errors,
+            "spec.zookeeper.resources",
+            &zookeeper.resources,
+        );
+        validate_tls(&mut errors, "spec.zookeeper.tls", &zookeeper.tls);
+        if zookeeper.tls.is_some() {
+            if zookeeper.external.is_some() {
+                errors.push(String::from(
+                    "spec.zookeeper.tls: cannot be set for an external ensemble",
+                ));
+            }
+            // without a version, the untagged image supports TLS
+            if let Some(version) = &zookeeper.version {
+                if !zookeeper::supports_tls(version) {
+                    errors.push(format!(
+                        "spec.zookeeper.tls: needs version 5.4 or later ({:?})",
+                        version
+                    ));
+                }
+            }
+        }
+        if let Some(backup) = &zookeeper.backup {
+            if backup.claim_name.is_some() == backup.s3.is_some() {
+                errors.push(String::from(
+                    "spec.zookeeper.backup: set exactly one of claim_name and s3",
+                ));
+            }
+            if zookeeper.external.is_some() {
+                errors.push(String::from(
+                    "spec.zookeeper.backup: cannot be set for an external ensemble",
+                ));
+            }
+        }
+        if let Some(restore) = &zookeeper.restore {
+            if restore.claim_name.is_some() == restore.s3.is_some() {
+                errors.push(String::from(
+                    "spec.zookeeper.restore: set exactly one of claim_name and s3",
+                ));
+            }
+            if zookeeper.external.is_some() {
+                errors.push(String::from(
+                    "spec.zookeeper.restore: cannot be set for an external ensemble",
+                ));
+            }
+        }
+    }
+
+    if let Some(ui) = &spec.noria_ui {
+        validate_version(&mut errors, "spec.noria_ui.version", &ui.version);
+        validate_resources(&mut errors, "spec.noria_ui.resources", &ui.resources);
+        if let Some(auth) = &ui.auth {
+            if auth.oauth2.is_some() == auth.basic.is_some() {
+                errors.push(String::from(
+                    "spec.noria_ui.auth: set exactly one of oauth2 and basic",
+                ));
+            }
+        }
+    }
+
+    errors
+}
+
+/// Changes from old to new that the operator cannot carry out
+pub fn validate_update(old: &Noria, new: &Noria) -> Vec<String> {
+    let mut errors = vec![];
+
+    if zookeeper::external_config(&old.spec.zookeeper).is_some()
+        != zookeeper::external_config(&new.spec.zookeeper).is_some()
+    {
+        errors.push(String::from(
+            "spec.zookeeper.external: cannot switch between an external and a managed ensemble",
+        ));
+    }
+
+    let (old_size, new_size) = (
+        zookeeper_storage_size(&old.spec),
+        zookeeper_storage_size(&new.spec),
+    );
+    if new_size < old_size {
+        errors.push(format!(
+            "spec.zookeeper.storage_size: cannot shrink storage from {} to {} MiB",
+            old_size, new_size
+        ));
+    }
+
+    for (i, deployment) in new.spec.deployments.iter().enumerate() {
+        let old_deployment = match old.spec.deployments.iter().find(|d| d.id == deployment.id) {
+            Some(old_deployment) => old_deployment,
+            None => continue,
+        };
+
+        let (old_size, new_size) = (
+            server_storage_size(old_deployment),
+            server_storage_size(deployment),
+        );
+        if new_size < old_size {
+            errors.push(format!(
+                "spec.deployments[{}].noria_server.storage_size: cannot shrink storage from {} to {} MiB",
+                i, old_size, new_size
+            ));
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roperator::serde_json::{self, json};
+
+    fn noria(name: &str, spec: Value) -> Noria {
+        serde_json::from_value(json!({
+            "metadata": {
+                "name": name,
+                "namespace": "default",
+            },
+            "spec": spec,
+        }))
+        .expect("Noria")
+    }
+
+    fn deployment(noria_server: Value) -> Value {
+        json!({ "deployments": [{ "id": "xx", "noria_server": noria_server }] })
+    }
+
+    #[test]
+    fn valid_specs_pass() {
+        let cases = vec![
+            json!({ "deployments": [{ "id": "xx" }, { "id": "aa" }] }),
+            json!({ "deployments": [], "zookeeper": { "replicas": 5, "storage_size": 1024 } }),
+            deployment(json!({ "version": "v1.2_3", "storage_size": 2048 })),
+            deployment(json!({ "disruption_budget": { "min_available": "50%" } })),
+            deployment(json!({ "resources": { "limits": { "cpu": "500m", "memory": "1Gi" } } })),
+        ];
+
+        for spec in cases {
+            let errors = validate(&noria("test", spec.clone()));
+            assert!(errors.is_empty(), "{}: {:?}", spec, errors);
+        }
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        let long_id = "x".repeat(28);
+        let long_name = "x".repeat(40);
+
+        let cases = vec![
+            (
+                "test",
+                json!({ "deployments": [{ "id": "xx" }, { "id": "xx" }] }),
+                "spec.deployments[1].id: duplicate id",
+            ),
+            (
+                "test",
+                json!({ "deployments": [{ "id": "XX" }] }),
+                "spec.deployments[0].id: must be a lowercase DNS label",
+            ),
+            (
+                "test",
+                json!({ "deployments": [{ "id": "x-y" }] }),
+                "spec.deployments[0].id: must not contain dashes",
+            ),
+            (
+                "test",
+                json!({ "deployments": [], "zookeeper": { "replicas": 4 } }),
+                "spec.zookeeper.replicas: must be an odd number",
+            ),
+            (
+                "test",
+                json!({ "deployments": [], "zookeeper": { "replicas": 0 } }),
+                "spec.zookeeper.replicas: must be an odd number",
+            ),
+            (
+                "test",
+                json!({ "deployments": [], "zookeeper": { "storage_size": 512 } }),
+                "spec.zookeeper.storage_size: must be at least",
+            ),
+            (
+                "test",
+                deployment(json!({ "storage_size": 100 })),
+                "spec.deployments[0].noria_server.storage_size: must be at least",
+            ),
+            (
+                "test",
+                json!({ "deployments": [], "zookeeper": { "version": "-5.4" } }),
+                "spec.zookeeper.version: invalid version",
+            ),
+            (
+                "test",
+                deployment(json!({ "version": "1.0 beta" })),
+                "spec.deployments[0].noria_server.version: invalid version",
+            ),
+            (
+                "test",
+                json!({ "deployments": [{ "id": long_id }] }),
+                "spec.deployments[0].id: makes the child name",
+            ),
+            (
+                &long_name,
+                json!({ "deployments": [] }),
+                "metadata.name: makes the child name",
+            ),
+            (
+                "test",
+                deployment(json!({ "disruption_budget": { "max_unavailable": "half" } })),
+                "spec.deployments[0].noria_server.disruption_budget.max_unavailable",
+            ),
+            (
+                "test",
+                deployment(json!({ "disruption_budget": { "min_available": -1 } })),
+                "spec.deployments[0].noria_server.disruption_budget.min_available",
+            ),
+            (
+                "test",
+                deployment(json!({ "resources": { "limits": { "memory": "lots" } } })),
+                "spec.deployments[0].noria_server.resources.limits.memory: invalid quantity",
+            ),
+        ];
+
+        for (name, spec, expected) in cases {
+            let errors = validate(&noria(name, spec.clone()));
+            assert!(
+                errors.iter().any(|error| error.starts_with(expected)),
+                "{}: expected {:?} in {:?}",
+                spec,
+                expected,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn storage_cannot_shrink() {
+        let cases = vec![
+            (
+                json!({ "deployments": [], "zookeeper": { "storage_size": 4096 } }),
+                json!({ "deployments": [], "zookeeper": { "storage_size": 2048 } }),
+                Some("spec.zookeeper.storage_size: cannot shrink"),
+            ),
+            (
+                deployment(json!({ "storage_size": 4096 })),
+                deployment(json!({ "storage_size": 2048 })),
+                Some("spec.deployments[0].noria_server.storage_size: cannot shrink"),
+            ),
+            (
+                deployment(json!({ "storage_size": 2048 })),
+                deployment(json!({ "storage_size": 4096 })),
+                None,
+            ),
+            (
+                deployment(json!({ "storage_size": 4096 })),
+                json!({ "deployments": [{ "id": "aa", "noria_server": { "storage_size": 2048 } }] }),
+                None,
+            ),
+        ];
+
+        for (old, new, expected) in cases {
+            let errors = validate_update(&noria("test", old), &noria("test", new.clone()));
+            match expected {
+                Some(expected) => assert!(
+                    errors.iter().any(|error| error.starts_with(expected)),
+                    "{}: expected {:?} in {:?}",
+                    new,
+                    expected,
+                    errors
+                ),
+                None => assert!(errors.is_empty(), "{}: {:?}", new, errors),
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use super::model::Noria;
+use super::validate;
+
+use roperator::prelude::*;
+use roperator::serde_json::{self, json, Value};
+
+use tiny_http::{Header, Method, Response, Server, SslConfig};
+
+use std::fs;
+use std::path::Path;
+
+use log::*;
+
+pub const VALIDATE_PATH: &str = "/validate";
+
+// Admission requests carry the namespace separately from the object
+fn deserialize_noria(object: &Value, namespace: &str) -> Result<Noria, serde_json::Error> {
+    let mut object = object.clone();
+    if let Some(metadata) = object
+        .get_mut("metadata")
+        .and_then(|metadata| metadata.as_object_mut())
+    {
+        metadata
+            .entry("namespace")
+            .or_insert_with(|| Value::String(namespace.to_string()));
+    }
+    serde_json::from_value(object)
+}
+
+fn review(request: &Value) -> Vec<String> {
+    let namespace = request["namespace"].as_str().unwrap_or_default();
+
+    let new = match deserialize_noria(&request["object"], namespace) {
+        Ok(new) => new,
+        Err(err) => return vec![format!("invalid Noria resource: {}", err)],
+    };
+
+    let mut errors = validate::validate(&new);
+
+    if request["operation"] == "UPDATE" {
+        // an old object that no longer deserializes must not block fixing it
+        if let Ok(old) = deserialize_noria(&request["oldObject"], namespace) {
+            errors.append(&mut validate::validate_update(&old, &new));
+        }
+    }
+
+    errors
+}
+
+fn handle(body: &str) -> Value {
+    let admission_review: Value = match serde_json::from_str(body) {
+        Ok(admission_review) => admission_review,
+        Err(err) => {
+            warn!("Invalid AdmissionReview: {}", err);
+            return json!({ "error": err.to_string() });
+        }
+    };
+
+    let request = &admission_review["request"];
+    let errors = review(request);
+
+    let mut response = json!({
+        "uid": request["uid"],
+        "allowed": errors.is_empty(),
+    });
+
+    if !errors.is_empty() {
+        info!(
+            "Rejected {} of {}/{}: {}",
+            request["operation"],
+            request["namespace"],
+            request["name"],
+            errors.join("; ")
+        );
+        response["status"] = json!({
+            "code": 422,
+            "reason": "Invalid",
+            "message": errors.join("; "),
+        });
+    }
+
+    json!({
+        "apiVersion": admission_review["apiVersion"],
+        "kind": "AdmissionReview",
+        "response": response,
+    })
+}
+
+/// Serves the validating admission webhook over HTTPS, never returns unless
+/// the server cannot be started
+pub fn run(port: u16, tls_cert: &Path, tls_key: &Path) -> Error {
+    let ssl_config = match (fs::read(tls_cert), fs::read(tls_key)) {
+        (Ok(certificate), Ok(private_key)) => SslConfig {
+            certificate,
+            private_key,
+        },
+        (Err(err), _) | (_, Err(err)) => return Box::new(err),
+    };
+
+    let server = match Server::https(("0.0.0.0", port), ssl_config) {
+        Ok(server) => server,
+        Err(err) => return err,
+    };
+
+    info!("Admission webhook listening on port {}", port);
+
+    for mut request in server.incoming_requests() {
+        // the API server appends a timeout query to the configured path
+        let path = request.url().split('?').next().unwrap_or_default();
+
+        let response = if *request.method() != Method::Post || path != VALIDATE_PATH {
+            Response::from_string("Not found").with_status_code(404)
+        } else {
+            let mut body = String::new();
+            match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => Response::from_string(handle(&body).to_string()).with_header(
+                    "Content-Type: application/json"
+                        .parse::<Header>()
+                        .expect("valid header"),
+                ),
+                Err(err) => Response::from_string(err.to_string()).with_status_code(400),
+            }
+        };
+
+        if let Err(err) = request.respond(response) {
+            warn!("Failed to respond to admission request: {}", err);
+        }
+    }
+
+    Box::new(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "Admission webhook server stopped",
+    ))
+}
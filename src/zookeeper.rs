@@ -1,24 +1,97 @@
+use super::disruption;
+use super::error;
+use super::monitoring;
+use super::resources::{self, DEFAULT_MEMORY_MULTIPLIER};
+use super::scheduling;
+use super::snapshot;
+use super::storage;
+use super::tls;
 use super::Builder;
-use super::ZookeeperConfig;
 use super::CONFLUENT_ZOOKEEPER_IMAGE;
+use super::{ExternalZookeeperConfig, SchedulingConfig, StorageConfig, TlsConfig, ZookeeperConfig};
+use super::{MonitoringConfig, ZookeeperBackupConfig, ZookeeperRestoreConfig};
 
+use k8s_types::apps::v1 as apps;
+
+use roperator::prelude::*;
 use roperator::serde_json::{json, Value};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
 // Default Zookeeper settings
 const DEFAULT_ZOOKEEPER_MAX_HEAP: u64 = 512;
-const DEFAULT_ZOOKEEPER_STORAGE_SIZE: u64 = 1024;
-const DEFAULT_ZOOKEEPER_REPLICAS: usize = 3;
-const DEFAULT_ZOOKEEPER_VERSION: &str = "5.3.3";
+pub const DEFAULT_ZOOKEEPER_STORAGE_SIZE: u64 = 1024;
+pub const DEFAULT_ZOOKEEPER_REPLICAS: usize = 3;
+pub const DEFAULT_ZOOKEEPER_VERSION: &str = "5.3.3";
+
+// Certificate Secret mount, and the PKCS12 stores converted from it at startup
+const TLS_SECRET_PATH: &str = "/etc/zookeeper/tls";
+const TLS_STORE_PATH: &str = "/var/run/zookeeper/tls";
+const TLS_KEYSTORE: &str = "/var/run/zookeeper/tls/keystore.p12";
+const TLS_TRUSTSTORE: &str = "/var/run/zookeeper/tls/truststore.p12";
+// The stores only live in the pod's memory, the password merely satisfies the JVM
+const TLS_STORE_PASSWORD: &str = "zookeeper";
+
+const QUORUM_TLS_ANNOTATION: &str = "noria-operator.io/quorum-tls";
+
+// Steps of switching a running ensemble's quorum traffic to TLS, each rolled
+// out to every member before the next. Members that only speak TLS can't
+// reach members that don't, so all of them first accept both.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum QuorumTls {
+    // accept TLS and plaintext quorum connections, connect in plaintext
+    Unified,
+    // accept both, connect with TLS
+    Encrypting,
+    // TLS only
+    Encrypted,
+}
+
+impl QuorumTls {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QuorumTls::Unified => "unified",
+            QuorumTls::Encrypting => "encrypting",
+            QuorumTls::Encrypted => "encrypted",
+        }
+    }
+
+    fn parse(phase: &str) -> Option<QuorumTls> {
+        match phase {
+            "unified" => Some(QuorumTls::Unified),
+            "encrypting" => Some(QuorumTls::Encrypting),
+            "encrypted" => Some(QuorumTls::Encrypted),
+            _ => None,
+        }
+    }
+}
 
 pub struct Config<'zk> {
-    name: &'zk str,
+    name: String,
+    noria_name: &'zk str,
     version: &'zk str,
+    image: String,
     max_heap: u64,
+    resources: Value,
+    scheduling: Option<&'zk SchedulingConfig>,
     storage_size: u64,
+    storage: Option<&'zk StorageConfig>,
     replicas: usize,
+    target_replicas: usize,
     properties: Vec<(&'zk str, &'zk str)>,
+    tls: Option<&'zk TlsConfig>,
+    quorum_tls: Option<QuorumTls>,
+    backup: Option<&'zk ZookeeperBackupConfig>,
+    restore: Option<&'zk ZookeeperRestoreConfig>,
+    monitoring: Option<&'zk MonitoringConfig>,
+    secret_checksums: Value,
+}
+
+/// State of the ZooKeeper StatefulSet currently running in the cluster
+pub struct Observed {
+    replicas: usize,
+    settled: bool,
+    quorum_tls: Option<QuorumTls>,
 }
 
 fn calculate_hash<T: Hash>(t: &T) -> String {
@@ -28,11 +101,152 @@ fn calculate_hash<T: Hash>(t: &T) -> String {
     hex::encode(b)
 }
 
+/// Name of the managed ensemble's StatefulSet
+pub fn name(noria_name: &str) -> String {
+    format!("zookeeper-{}", noria_name)
+}
+
+pub fn client_service_name(noria_name: &str) -> String {
+    format!("zookeeper-{}-client", noria_name)
+}
+
+fn tls_config(zookeeper: &Option<ZookeeperConfig>) -> Option<&TlsConfig> {
+    match zookeeper {
+        Some(ZookeeperConfig { tls: Some(tls), .. }) => Some(tls),
+        _ => None,
+    }
+}
+
+/// Certificate Secret of a managed ensemble with TLS enabled
+pub fn tls_secret(noria_name: &str, zookeeper: &Option<ZookeeperConfig>) -> Option<String> {
+    match external_config(zookeeper) {
+        Some(_) => None,
+        None => tls_config(zookeeper).map(|tls| tls::secret_name(tls, &name(noria_name))),
+    }
+}
+
+// secureClientPort and quorum TLS arrived with ZooKeeper 3.5, which
+// Confluent Platform ships from 5.4 onwards. Custom tags are let through.
+pub fn supports_tls(version: &str) -> bool {
+    let mut parts = version.split('.').map(str::parse::<u32>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => (major, minor) >= (5, 4),
+        _ => true,
+    }
+}
+
+pub fn external_config(zookeeper: &Option<ZookeeperConfig>) -> Option<&ExternalZookeeperConfig> {
+    match zookeeper {
+        Some(ZookeeperConfig {
+            external: Some(external),
+            ..
+        }) => Some(external),
+        _ => None,
+    }
+}
+
+// Connect string handed to noria-server and noria-mysql
+pub fn address(noria_name: &str, zookeeper: &Option<ZookeeperConfig>) -> String {
+    match external_config(zookeeper) {
+        Some(ExternalZookeeperConfig {
+            connect_string,
+            chroot: Some(chroot),
+        }) => format!("{}{}", connect_string, chroot),
+        Some(ExternalZookeeperConfig { connect_string, .. }) => connect_string.clone(),
+        None => format!("{}:2181", client_service_name(noria_name)),
+    }
+}
+
+pub fn observe(request: &SyncRequest, noria_name: &str, namespace: &str) -> Option<Observed> {
+    let statefulset = request
+        .children()
+        .of_type(apps::StatefulSet)
+        .get(namespace, &name(noria_name))?;
+
+    let field = |path: &str| statefulset.pointer(path).and_then(Value::as_u64);
+    let revision = |path: &str| statefulset.pointer(path).and_then(Value::as_str);
+
+    let replicas = field("/spec/replicas").unwrap_or(0) as usize;
+
+    Some(Observed {
+        replicas,
+        // every member restarted with the current properties and rejoined the quorum
+        settled: field("/status/observedGeneration") >= field("/metadata/generation")
+            && field("/status/readyReplicas").unwrap_or(0) as usize == replicas
+            && revision("/status/currentRevision") == revision("/status/updateRevision"),
+        quorum_tls: statefulset["metadata"]["annotations"][QUORUM_TLS_ANNOTATION]
+            .as_str()
+            .and_then(QuorumTls::parse),
+    })
+}
+
+// A new ensemble starts with TLS only, a running one that does not use TLS yet
+// moves one step further each time all members restarted with the previous one
+fn quorum_tls(observed: &Option<Observed>) -> QuorumTls {
+    match observed {
+        None => QuorumTls::Encrypted,
+        Some(Observed {
+            quorum_tls: None, ..
+        }) => QuorumTls::Unified,
+        Some(Observed {
+            quorum_tls: Some(phase),
+            settled: false,
+            ..
+        }) => *phase,
+        Some(Observed {
+            quorum_tls: Some(QuorumTls::Unified),
+            ..
+        }) => QuorumTls::Encrypting,
+        Some(Observed {
+            quorum_tls: Some(_),
+            ..
+        }) => QuorumTls::Encrypted,
+    }
+}
+
+// Moves the ensemble one member closer to the target. Every step changes the
+// server list, which rolls the members one at a time. With a single member
+// between the old and the new list, no two quorums of the old and new
+// configuration can be disjoint during the roll, so the next step waits until
+// the StatefulSet is fully ready with the new list.
+fn ensemble_size(observed: &Option<Observed>, target: usize) -> Result<usize, Error> {
+    if target % 2 == 0 {
+        return Err(Box::new(error::ZookeeperReplicasError { replicas: target }));
+    }
+
+    let current = match observed {
+        Some(observed) if observed.replicas != target => observed,
+        _ => return Ok(target),
+    };
+
+    // a single member runs standalone, without a quorum to hand over
+    if current.replicas <= 1 || target <= 1 {
+        return Err(Box::new(error::ZookeeperScalingError {
+            from: current.replicas,
+            to: target,
+        }));
+    }
+
+    if !current.settled {
+        return Ok(current.replicas);
+    }
+
+    if target > current.replicas {
+        Ok(current.replicas + 1)
+    } else {
+        Ok(current.replicas - 1)
+    }
+}
+
 pub fn create_config<'zk>(
+    noria_name: &'zk str,
     zookeeper: &'zk Option<ZookeeperConfig>,
+    observed: &Option<Observed>,
     extend_properties: &'zk mut Vec<(String, String)>,
-) -> Config<'zk> {
-    let zookeeper_name = "zookeeper-noria";
+    secret_checksums: Value,
+    monitoring: Option<&'zk MonitoringConfig>,
+) -> Result<Config<'zk>, Error> {
+    let zookeeper_name = name(noria_name);
     let zookeeper_max_heap = match zookeeper {
         Some(ZookeeperConfig {
             max_heap: Some(m), ..
@@ -40,6 +254,41 @@ pub fn create_config<'zk>(
         _ => DEFAULT_ZOOKEEPER_MAX_HEAP,
     };
 
+    let zookeeper_memory_multiplier = match zookeeper {
+        Some(ZookeeperConfig {
+            memory_multiplier: Some(m),
+            ..
+        }) => *m,
+        _ => DEFAULT_MEMORY_MULTIPLIER,
+    };
+
+    let zookeeper_resources = match zookeeper {
+        Some(ZookeeperConfig { resources, .. }) => resources,
+        None => &None,
+    };
+
+    // -Xmx follows an explicit memory limit
+    let zookeeper_max_heap = resources::heap_size(
+        zookeeper_max_heap,
+        zookeeper_memory_multiplier,
+        zookeeper_resources,
+    );
+
+    let zookeeper_scheduling = match zookeeper {
+        Some(ZookeeperConfig {
+            scheduling: Some(s),
+            ..
+        }) => Some(s),
+        _ => None,
+    };
+
+    let zookeeper_storage = match zookeeper {
+        Some(ZookeeperConfig {
+            storage: Some(s), ..
+        }) => Some(s),
+        _ => None,
+    };
+
     let zookeeper_storage_size = match zookeeper {
         Some(ZookeeperConfig {
             storage_size: Some(m),
@@ -55,13 +304,50 @@ pub fn create_config<'zk>(
         _ => DEFAULT_ZOOKEEPER_REPLICAS,
     };
 
-    let zookeeper_version = match zookeeper {
+    let zookeeper_ensemble_size = ensemble_size(observed, zookeeper_replicas)?;
+
+    let pinned_version = match zookeeper {
         Some(ZookeeperConfig {
             version: Some(v), ..
-        }) => v,
-        _ => DEFAULT_ZOOKEEPER_VERSION,
+        }) => Some(v.as_str()),
+        _ => None,
     };
 
+    let zookeeper_version = pinned_version.unwrap_or(DEFAULT_ZOOKEEPER_VERSION);
+
+    // Without a version, ZooKeeper keeps running the untagged image it always
+    // ran, so that existing ensembles are not restarted onto another release
+    let zookeeper_image = match pinned_version {
+        Some(v) => format!("{}:{}", CONFLUENT_ZOOKEEPER_IMAGE, v),
+        None => CONFLUENT_ZOOKEEPER_IMAGE.to_string(),
+    };
+
+    let zookeeper_tls = tls_config(zookeeper);
+    let zookeeper_quorum_tls = zookeeper_tls.map(|_| quorum_tls(observed));
+
+    let zookeeper_backup = match zookeeper {
+        Some(ZookeeperConfig {
+            backup: Some(b), ..
+        }) => Some(b),
+        _ => None,
+    };
+
+    let zookeeper_restore = match zookeeper {
+        Some(ZookeeperConfig {
+            restore: Some(r), ..
+        }) => Some(r),
+        _ => None,
+    };
+
+    // the untagged image is a current release, only pinned versions are checked
+    if let (Some(_), Some(version)) = (zookeeper_tls, pinned_version) {
+        if !supports_tls(version) {
+            return Err(Box::new(error::ZookeeperTlsVersionError {
+                version: version.to_string(),
+            }));
+        }
+    }
+
     let mut zookeeper_properties = vec![
         ("autopurge.purgeInterval", "1"),
         ("tickTime", "2000"),
@@ -74,7 +360,7 @@ pub fn create_config<'zk>(
 
     //let mut extend_properties = vec![];
     let zookeeper_nodes_service_name = format!("{}-nodes", zookeeper_name);
-    for n in 0..zookeeper_replicas {
+    for n in 0..zookeeper_ensemble_size {
         extend_properties.push((
             format!("server.{}", n + 1),
             format!(
@@ -84,6 +370,38 @@ pub fn create_config<'zk>(
         ));
     }
 
+    // Noria's clients keep using the plaintext port, TLS clients use the secure
+    // one. Port unification lets members accept both kinds of quorum
+    // connections while TLS is switched on.
+    if let Some(phase) = zookeeper_quorum_tls {
+        zookeeper_properties.extend_from_slice(&[
+            ("secureClientPort", "2281"),
+            (
+                "serverCnxnFactory",
+                "org.apache.zookeeper.server.NettyServerCnxnFactory",
+            ),
+            ("ssl.keyStore.location", TLS_KEYSTORE),
+            ("ssl.keyStore.password", TLS_STORE_PASSWORD),
+            ("ssl.keyStore.type", "PKCS12"),
+            ("ssl.trustStore.location", TLS_TRUSTSTORE),
+            ("ssl.trustStore.password", TLS_STORE_PASSWORD),
+            ("ssl.trustStore.type", "PKCS12"),
+            ("ssl.quorum.keyStore.location", TLS_KEYSTORE),
+            ("ssl.quorum.keyStore.password", TLS_STORE_PASSWORD),
+            ("ssl.quorum.keyStore.type", "PKCS12"),
+            ("ssl.quorum.trustStore.location", TLS_TRUSTSTORE),
+            ("ssl.quorum.trustStore.password", TLS_STORE_PASSWORD),
+            ("ssl.quorum.trustStore.type", "PKCS12"),
+            // peers connect by pod IP, they are authenticated by the CA instead
+            ("ssl.quorum.hostnameVerification", "false"),
+        ]);
+        zookeeper_properties.extend_from_slice(match phase {
+            QuorumTls::Unified => &[("portUnification", "true")],
+            QuorumTls::Encrypting => &[("sslQuorum", "true"), ("portUnification", "true")],
+            QuorumTls::Encrypted => &[("sslQuorum", "true")],
+        });
+    }
+
     // is there a better way of doing this?
     zookeeper_properties.append(
         &mut extend_properties
@@ -96,23 +414,43 @@ pub fn create_config<'zk>(
         Some(ZookeeperConfig {
             additional_properties: Some(props),
             ..
-        }) => zookeeper_properties.extend_from_slice(
-            &props
+        }) => {
+            // sorted, so that the properties checksum is stable between syncs
+            let mut props = props
                 .iter()
                 .map(|(key, val)| (key.as_str(), val.as_str()))
-                .collect::<Vec<(&str, &str)>>(),
-        ),
+                .collect::<Vec<(&str, &str)>>();
+            props.sort();
+            zookeeper_properties.extend_from_slice(&props)
+        }
         _ => (),
     };
 
-    Config {
+    Ok(Config {
         name: zookeeper_name,
+        noria_name,
         version: zookeeper_version,
+        image: zookeeper_image,
         max_heap: zookeeper_max_heap,
+        resources: resources::container_resources(
+            Some(zookeeper_max_heap),
+            zookeeper_memory_multiplier,
+            zookeeper_resources,
+            json!({}),
+        ),
+        scheduling: zookeeper_scheduling,
         storage_size: zookeeper_storage_size,
-        replicas: zookeeper_replicas,
+        storage: zookeeper_storage,
+        replicas: zookeeper_ensemble_size,
+        target_replicas: zookeeper_replicas,
         properties: zookeeper_properties,
-    }
+        tls: zookeeper_tls,
+        quorum_tls: zookeeper_quorum_tls,
+        backup: zookeeper_backup,
+        restore: zookeeper_restore,
+        monitoring,
+        secret_checksums,
+    })
 }
 
 impl<'zk> Builder for Config<'zk> {
@@ -122,10 +460,36 @@ impl<'zk> Builder for Config<'zk> {
         let chksum = calculate_hash(&self.properties);
 
         let zookeeper_properties_name = format!("{}-properties-{}", self.name, chksum);
-        let zookeeper_client_service_name = format!("{}-client", self.name);
+        let zookeeper_client_service_name = client_service_name(self.noria_name);
         let zookeeper_nodes_service_name = format!("{}-nodes", self.name);
 
-        let zookeeper_docker_mem = (1.3 * (self.max_heap as f32)) as u64;
+        let mut client_ports = vec![json!({
+            "port": 2181,
+            "name": "clients",
+            "targetPort": 2181,
+        })];
+        if self.tls.is_some() {
+            client_ports.push(json!({
+                "port": 2281,
+                "name": "clients-tls",
+                "targetPort": 2281,
+            }));
+        }
+
+        let mut node_ports = client_ports.clone();
+        node_ports.extend(vec![
+            json!({
+                "port": 2888,
+                "name": "clustering",
+                "targetPort": 2888,
+            }),
+            json!({
+                "port": 3888,
+                "name": "leader-election",
+                "targetPort": 3888,
+            }),
+        ]);
+
         children.push(json!({
             "apiVersion": "v1",
             "kind": "ConfigMap",
@@ -150,25 +514,13 @@ impl<'zk> Builder for Config<'zk> {
                 "namespace": namespace,
             },
             "spec": {
-                "ports": [{
-                    "port": 2181,
-                    "name": "clients",
-                    "targetPort": 2181,
-                },{
-                    "port": 2888,
-                    "name": "clustering",
-                    "targetPort": 2888,
-                },{
-                    "port": 3888,
-                    "name": "leader-election",
-                    "targetPort": 3888,
-                }],
+                "ports": node_ports,
                 "publishNotReadyAddresses": true,
                 "clusterIP": "None",
                 "sessionAffinity": "None",
                 "selector": {
                     "noria-operator.io/kind": "zookeeper",
-                    "noria-operator.io/name": "noria"
+                    "noria-operator.io/name": self.noria_name
                 }
             }
         }));
@@ -181,25 +533,44 @@ impl<'zk> Builder for Config<'zk> {
                 "namespace": namespace,
             },
             "spec": {
-                "ports": [{
-                    "port": 2181,
-                    "name": "clients",
-                    "targetPort": 2181,
-                }],
+                "ports": client_ports,
                 "selector": {
                     "noria-operator.io/kind": "zookeeper",
-                    "noria-operator.io/name": "noria"
+                    "noria-operator.io/name": self.noria_name
                 }
             }
         }));
 
+        if let Some(tls) = self.tls {
+            if let Some(certificate) = tls::certificate(
+                tls,
+                &self.name,
+                namespace,
+                vec![
+                    zookeeper_client_service_name.clone(),
+                    format!("{}.{}.svc", zookeeper_client_service_name, namespace),
+                    format!("*.{}", zookeeper_nodes_service_name),
+                    format!("*.{}.{}.svc", zookeeper_nodes_service_name, namespace),
+                ],
+            ) {
+                children.push(certificate);
+            }
+        }
+
         // Zookeeper StatefulSet
-        children.push(json!({
+        let mut statefulset = json!({
             "apiVersion": "apps/v1",
             "kind": "StatefulSet",
             "metadata": {
                 "name": self.name,
                 "namespace": namespace,
+                "labels": {
+                    "noria-operator.io/kind": "zookeeper",
+                    "noria-operator.io/name": self.noria_name
+                },
+                "annotations": {
+                    "noria-operator.io/target-replicas": self.target_replicas.to_string()
+                }
             },
             "spec": {
                 "podManagementPolicy": "Parallel",
@@ -208,7 +579,7 @@ impl<'zk> Builder for Config<'zk> {
                 "selector": {
                     "matchLabels": {
                         "noria-operator.io/kind": "zookeeper",
-                        "noria-operator.io/name": "noria",
+                        "noria-operator.io/name": self.noria_name,
                     }
                 },
                 "template": {
@@ -216,7 +587,7 @@ impl<'zk> Builder for Config<'zk> {
                         "name": self.name,
                         "labels": {
                             "noria-operator.io/kind": "zookeeper",
-                            "noria-operator.io/name": "noria"
+                            "noria-operator.io/name": self.noria_name
                         }
                     },
                     "spec": {
@@ -239,7 +610,7 @@ impl<'zk> Builder for Config<'zk> {
                                 "name": "KAFKA_HEAP_OPTS",
                                 "value": format!("-Xmx{}m", self.max_heap)
                             }],
-                            "image": CONFLUENT_ZOOKEEPER_IMAGE,
+                            "image": self.image,
                             "imagePullPolicy": "IfNotPresent",
                             "name": "zookeeper",
                             "ports": [{
@@ -255,13 +626,20 @@ impl<'zk> Builder for Config<'zk> {
                                 "name": "leader-election",
                                 "protocol": "TCP",
                             }],
-                            "resources": {
-                                "limits": {
-                                    "memory": format!("{}Mi", zookeeper_docker_mem)
+                            "resources": self.resources,
+                            "readinessProbe": {
+                                "exec": {
+                                    "command": [
+                                        "bash",
+                                        "-exc",
+                                        r#"echo stat | nc 127.0.0.1 2181 | grep -q '^Mode: '"#,
+                                    ]
                                 },
-                                "requests": {
-                                    "memory": format!("{}Mi", zookeeper_docker_mem)
-                                }
+                                "failureThreshold": 3,
+                                "initialDelaySeconds": 10,
+                                "periodSeconds": 10,
+                                "successThreshold": 1,
+                                "timeoutSeconds": 5,
                             },
                             "livenessProbe": {
                                 "exec": {
@@ -295,26 +673,287 @@ impl<'zk> Builder for Config<'zk> {
                     }
                 },
                 "updateStrategy": {
-                    "type": "OnDelete"
+                    "type": "RollingUpdate"
                 },
 
-                "volumeClaimTemplates": [{
-                    "metadata": {
-                        "name": "data"
-                    },
-                    "spec": {
-                        "accessModes": ["ReadWriteOnce"],
-                        "resources": {
-                            "requests": {
-                                "storage": format!("{}Gi",  self.storage_size / 1024 as u64)
-                            }
-                        }
+                "volumeClaimTemplates": [
+                    storage::volume_claim_template("data", self.storage_size, self.storage)
+                ]
+            }
+
+        });
+
+        if let Some(policy) = storage::retention_policy(self.storage) {
+            statefulset["spec"]["persistentVolumeClaimRetentionPolicy"] = policy;
+        }
+
+        if let Some(phase) = self.quorum_tls {
+            statefulset["metadata"]["annotations"][QUORUM_TLS_ANNOTATION] = json!(phase.as_str());
+        }
+
+        // One member per node, so that losing a node keeps the quorum
+        scheduling::apply(
+            &mut statefulset["spec"]["template"]["spec"],
+            self.scheduling,
+            Some(scheduling::spread_over_nodes(json!({
+                "noria-operator.io/kind": "zookeeper",
+                "noria-operator.io/name": self.noria_name,
+            }))),
+        );
+
+        // The stores are converted from the mounted Secret when the pod starts,
+        // a rotated certificate rolls the pods through the checksum annotation
+        if let Some(tls) = self.tls {
+            let secret = tls::secret_name(tls, &self.name);
+            statefulset["spec"]["template"]["metadata"]["annotations"] =
+                self.secret_checksums.clone();
+
+            let pod = &mut statefulset["spec"]["template"]["spec"];
+
+            if let Some(init_containers) = pod["initContainers"].as_array_mut() {
+                init_containers.push(json!({
+                    "name": "init-tls",
+                    "image": self.image,
+                    "command": ["bash", "-c", format!(
+                        r#"set -ex
+                        openssl pkcs12 -export -name zookeeper -passout pass:{password} \
+                          -in {secret}/tls.crt -inkey {secret}/tls.key -out {keystore}
+                        keytool -importcert -noprompt -alias ca -file {secret}/ca.crt \
+                          -storetype PKCS12 -keystore {truststore} -storepass {password}"#,
+                        password = TLS_STORE_PASSWORD,
+                        secret = TLS_SECRET_PATH,
+                        keystore = TLS_KEYSTORE,
+                        truststore = TLS_TRUSTSTORE,
+                    )],
+                    "volumeMounts": [{
+                        "mountPath": TLS_SECRET_PATH,
+                        "name": "tls",
+                        "readOnly": true
+                    }, {
+                        "mountPath": TLS_STORE_PATH,
+                        "name": "tls-stores"
+                    }]
+                }));
+            }
+
+            let container = &mut pod["containers"][0];
+            if let Some(ports) = container["ports"].as_array_mut() {
+                ports.push(json!({
+                    "containerPort": 2281,
+                    "name": "clients-tls",
+                    "protocol": "TCP",
+                }));
+            }
+            if let Some(volume_mounts) = container["volumeMounts"].as_array_mut() {
+                volume_mounts.push(json!({
+                    "mountPath": TLS_STORE_PATH,
+                    "name": "tls-stores",
+                    "readOnly": true
+                }));
+            }
+
+            if let Some(volumes) = pod["volumes"].as_array_mut() {
+                volumes.push(tls::volume(&secret));
+                volumes.push(json!({
+                    "name": "tls-stores",
+                    "emptyDir": {
+                        "medium": "Memory"
                     }
-                }]
+                }));
             }
+        }
 
-        }));
+        // A new member's data dir is filled from the snapshot before its id is
+        // written, members that have data already skip the restore
+        if let Some(restore) = self.restore {
+            let pod = &mut statefulset["spec"]["template"]["spec"];
+
+            if let Some(init_containers) = pod["initContainers"].as_array_mut() {
+                let restore_containers = snapshot::init_containers(restore, self.version);
+                init_containers.splice(0..0, restore_containers);
+            }
+            if let Some(volumes) = pod["volumes"].as_array_mut() {
+                volumes.push(snapshot::volume(restore));
+            }
+        }
+
+        // JMX only listens on the loopback interface, the exporter next to
+        // ZooKeeper turns its MBeans into Prometheus metrics
+        if let Some(monitoring) = self.monitoring {
+            let exporter_config_name = format!("{}-jmx-exporter", self.name);
+            children.push(monitoring::zookeeper_exporter_config(
+                &exporter_config_name,
+                namespace,
+            ));
+
+            let pod = &mut statefulset["spec"]["template"]["spec"];
+
+            if let Some(env) = pod["containers"][0]["env"].as_array_mut() {
+                env.push(json!({
+                    "name": "JMX_PORT",
+                    "value": monitoring::ZOOKEEPER_JMX_PORT.to_string()
+                }));
+                env.push(json!({
+                    "name": "KAFKA_JMX_OPTS",
+                    "value": monitoring::zookeeper_jmx_options()
+                }));
+            }
+            if let Some(containers) = pod["containers"].as_array_mut() {
+                containers.push(monitoring::zookeeper_exporter());
+            }
+            if let Some(volumes) = pod["volumes"].as_array_mut() {
+                volumes.push(monitoring::zookeeper_exporter_volume(&exporter_config_name));
+            }
+
+            children.append(&mut monitoring::children(
+                monitoring,
+                &self.name,
+                namespace,
+                json!({
+                    "noria-operator.io/kind": "zookeeper",
+                    "noria-operator.io/name": self.noria_name,
+                }),
+                self.noria_name,
+                None,
+                monitoring::METRICS_PORT,
+            ));
+        }
+
+        children.push(statefulset);
+
+        if let Some(backup) = self.backup {
+            children.append(
+                &mut snapshot::create_config(self.noria_name, backup, self.version)
+                    .children(namespace),
+            );
+        }
+
+        // Zookeeper PodDisruptionBudget, evictions never take the quorum. With
+        // fewer than three members no member can be evicted, and a budget of
+        // zero would block node drains.
+        if self.replicas >= 3 {
+            children.push(disruption::pod_disruption_budget(
+                &self.name,
+                namespace,
+                json!({
+                    "noria-operator.io/kind": "zookeeper",
+                    "noria-operator.io/name": self.noria_name,
+                }),
+                json!({ "maxUnavailable": (self.replicas - 1) / 2 }),
+            ));
+        }
 
         children
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observed(replicas: usize, settled: bool) -> Option<Observed> {
+        Some(Observed {
+            replicas,
+            settled,
+            quorum_tls: None,
+        })
+    }
+
+    fn observed_tls(settled: bool, quorum_tls: Option<QuorumTls>) -> Option<Observed> {
+        Some(Observed {
+            replicas: 3,
+            settled,
+            quorum_tls,
+        })
+    }
+
+    fn scaling_error(err: Error) -> (usize, usize) {
+        let err = err
+            .downcast::<error::ZookeeperScalingError>()
+            .expect("ZookeeperScalingError");
+        (err.from, err.to)
+    }
+
+    #[test]
+    fn new_ensemble_starts_at_target() {
+        assert_eq!(ensemble_size(&None, 5).unwrap(), 5);
+        assert_eq!(ensemble_size(&None, 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn settled_at_target_stays() {
+        assert_eq!(ensemble_size(&observed(3, true), 3).unwrap(), 3);
+        assert_eq!(ensemble_size(&observed(3, false), 3).unwrap(), 3);
+    }
+
+    #[test]
+    fn grows_one_member_at_a_time() {
+        assert_eq!(ensemble_size(&observed(3, true), 7).unwrap(), 4);
+        assert_eq!(ensemble_size(&observed(4, true), 7).unwrap(), 5);
+        assert_eq!(ensemble_size(&observed(6, true), 7).unwrap(), 7);
+    }
+
+    #[test]
+    fn shrinks_one_member_at_a_time() {
+        assert_eq!(ensemble_size(&observed(7, true), 3).unwrap(), 6);
+        assert_eq!(ensemble_size(&observed(6, true), 3).unwrap(), 5);
+        assert_eq!(ensemble_size(&observed(4, true), 3).unwrap(), 3);
+    }
+
+    #[test]
+    fn unsettled_ensemble_waits() {
+        assert_eq!(ensemble_size(&observed(3, false), 7).unwrap(), 3);
+        assert_eq!(ensemble_size(&observed(4, false), 7).unwrap(), 4);
+        assert_eq!(ensemble_size(&observed(7, false), 3).unwrap(), 7);
+    }
+
+    #[test]
+    fn even_target_is_rejected() {
+        let err = ensemble_size(&observed(3, true), 4).unwrap_err();
+        assert!(err
+            .downcast_ref::<error::ZookeeperReplicasError>()
+            .is_some());
+    }
+
+    #[test]
+    fn standalone_cannot_scale() {
+        let err = ensemble_size(&observed(1, true), 3).unwrap_err();
+        assert_eq!(scaling_error(err), (1, 3));
+
+        let err = ensemble_size(&observed(3, true), 1).unwrap_err();
+        assert_eq!(scaling_error(err), (3, 1));
+    }
+
+    #[test]
+    fn quorum_tls_steps_once_settled() {
+        use QuorumTls::*;
+
+        let cases = vec![
+            (None, Encrypted),
+            (observed_tls(true, None), Unified),
+            (observed_tls(false, None), Unified),
+            (observed_tls(true, Some(Unified)), Encrypting),
+            (observed_tls(false, Some(Unified)), Unified),
+            (observed_tls(true, Some(Encrypting)), Encrypted),
+            (observed_tls(false, Some(Encrypting)), Encrypting),
+            (observed_tls(true, Some(Encrypted)), Encrypted),
+            (observed_tls(false, Some(Encrypted)), Encrypted),
+        ];
+
+        for (observed, phase) in cases {
+            assert_eq!(quorum_tls(&observed), phase);
+        }
+    }
+
+    #[test]
+    fn quorum_tls_round_trips() {
+        for phase in &[
+            QuorumTls::Unified,
+            QuorumTls::Encrypting,
+            QuorumTls::Encrypted,
+        ] {
+            assert_eq!(QuorumTls::parse(phase.as_str()), Some(*phase));
+        }
+        assert_eq!(QuorumTls::parse("plaintext"), None);
+    }
+}